@@ -1,86 +1,933 @@
-use std::collections::BTreeSet;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
 use std::fs;
-use std::io::{self, IsTerminal, Read};
-use std::path::PathBuf;
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex, PoisonError};
+use std::time::SystemTime;
 
-use clap::{ArgAction, Parser, ValueHint};
-use serde::Serialize;
+use chardetng::{EncodingDetector, Iso2022JpDetection, Utf8Detection};
+use clap::parser::ValueSource;
+use clap::{
+    ArgAction, ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum, ValueHint,
+};
+use encoding_rs::Encoding;
+use globset::{GlobBuilder, GlobMatcher};
+use indicatif::{ProgressBar, ProgressStyle};
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
+/// When no subcommand is given the options below behave like `extract`, so
+/// existing `mdcode [OPTIONS] FILE...` invocations keep working. A file whose
+/// name collides with a subcommand can be passed as `./list`.
 #[derive(Parser, Debug)]
 #[command(
     name = "mdcode",
     about = "Extract fenced and inline code blocks from Markdown",
-    version
+    version,
+    args_conflicts_with_subcommands = true
 )]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    args: Args,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print code blocks (the default)
+    Extract(Args),
+    /// List blocks with metadata
+    List(Args),
+    /// List the languages found
+    Langs(Args),
+    /// Summarize block and line counts per language
+    Stats(Args),
+}
+
+impl Command {
+    fn into_args(self) -> Args {
+        match self {
+            Command::Extract(args) => args,
+            Command::List(mut args) => {
+                args.list = true;
+                args
+            }
+            Command::Langs(mut args) => {
+                args.lang = Some(None);
+                args
+            }
+            Command::Stats(mut args) => {
+                args.stats = true;
+                args
+            }
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
 struct Args {
     /// Target code block by index or range (e.g. 0, 1-3)
     #[arg(short = 'n', long = "number", value_name = "INDEX|RANGE")]
     number: Option<String>,
 
+    /// Keep only lines START-END (1-based) of the block selected with -n
+    #[arg(long = "lines", value_name = "START-END", requires = "number")]
+    lines: Option<String>,
+
     /// Filter by language; omit value to list languages found
     #[arg(long = "lang", num_args = 0..=1, value_name = "LANG")]
     lang: Option<Option<String>>,
 
+    /// Filter by the languages listed in FILE, one per line (`#` comments allowed)
+    #[arg(
+        long = "lang-file",
+        alias = "only-languages-in",
+        value_name = "FILE",
+        conflicts_with = "lang"
+    )]
+    lang_file: Option<PathBuf>,
+
+    /// Keep blocks whose info-string attributes match KEY=VALUE or KEY (repeatable)
+    #[arg(long = "attr", value_name = "KEY[=VALUE]")]
+    attr: Vec<String>,
+
+    /// Keep blocks in CATEGORY, e.g. code, diagram or math (repeatable)
+    #[arg(long = "category", value_name = "CATEGORY")]
+    category: Vec<String>,
+
+    /// Classify LANG as CATEGORY, overriding the built-in table (repeatable)
+    #[arg(long = "kind-from-lang", value_name = "LANG=CATEGORY")]
+    kind_from_lang: Vec<String>,
+
+    /// Keep only the lines between `BEGIN NAME` and `END NAME` markers, dropping blocks without them
+    #[arg(long = "region", value_name = "NAME")]
+    region: Option<String>,
+
+    /// Keep only blocks from sources matching NAME (path, file name or glob), renumbered from 0
+    #[arg(long = "in-source", value_name = "NAME")]
+    in_source: Option<String>,
+
+    /// Report on stderr which active filters each parsed block passed, and which one dropped it
+    #[arg(long = "explain", action = ArgAction::SetTrue)]
+    explain: bool,
+
+    /// Select the block whose lines, fences included, contain source line N
+    #[arg(long = "at-line", value_name = "N")]
+    at_line: Option<usize>,
+
+    /// Keep blocks whose code matches the regular expression PATTERN
+    #[arg(long = "grep", value_name = "PATTERN")]
+    grep: Option<String>,
+
+    /// Highlight --grep matches in raw output
+    #[arg(long = "highlight-matches", action = ArgAction::SetTrue, requires = "grep")]
+    highlight_matches: bool,
+
+    /// When to use ANSI colors
+    #[arg(
+        long = "color",
+        value_enum,
+        value_name = "WHEN",
+        default_value = "auto"
+    )]
+    color: ColorChoice,
+
+    /// Keep only fenced blocks whose closing fence is missing
+    #[arg(long = "unclosed-only", action = ArgAction::SetTrue)]
+    unclosed_only: bool,
+
+    /// Keep at most K blocks from each input file
+    #[arg(long = "max-per-source", value_name = "K")]
+    max_per_source: Option<usize>,
+
+    /// Keep blocks matching an expression over lang, kind, lines, source and index,
+    /// e.g. `lang==rust && lines>10`
+    #[arg(long = "where", value_name = "EXPR")]
+    where_expr: Option<String>,
+
+    /// After filtering, keep only the first block of each language (unlabeled blocks count as one)
+    #[arg(long = "first-per-lang", action = ArgAction::SetTrue)]
+    first_per_lang: bool,
+
+    /// After filtering, keep N blocks chosen uniformly at random, in index order
+    #[arg(long = "sample", value_name = "N")]
+    sample: Option<usize>,
+
+    /// Seed for --sample so the same blocks are picked on every run
+    #[arg(long = "seed", value_name = "S", requires = "sample")]
+    seed: Option<u64>,
+
+    /// Merge runs of consecutive same-language fenced blocks from one source
+    #[arg(long = "join-adjacent", action = ArgAction::SetTrue)]
+    join_adjacent: bool,
+
+    /// Keep the block whose content hash starts with HASH (see `hash` in --json)
+    #[arg(long = "hash-select", alias = "select-by-hash", value_name = "HASH")]
+    hash_select: Option<String>,
+
+    /// Lint mode: report block lines longer than N characters and exit nonzero
+    #[arg(long = "max-line-length", value_name = "N")]
+    max_line_length: Option<usize>,
+
+    /// Let lines containing a URL exceed --max-line-length
+    #[arg(long = "ignore-urls", action = ArgAction::SetTrue, requires = "max_line_length")]
+    ignore_urls: bool,
+
+    /// Lint mode: report fences whose delimiter differs from the document's first fence and exit nonzero
+    #[arg(long = "warn-mixed-fences", action = ArgAction::SetTrue)]
+    warn_mixed_fences: bool,
+
+    /// Lint mode: report every fence not delimited by CHAR and exit nonzero
+    #[arg(long = "require-fence-char", value_enum, value_name = "CHAR")]
+    require_fence_char: Option<FenceChar>,
+
+    /// Lint mode: report fences whose opening is not exactly N characters long and exit nonzero
+    #[arg(
+        long = "require-fence-len",
+        value_name = "N",
+        value_parser = clap::value_parser!(u64).range(3..)
+    )]
+    require_fence_len: Option<u64>,
+
+    /// Lint mode: report block lines containing a tab character and exit nonzero
+    #[arg(long = "warn-on-tabs", action = ArgAction::SetTrue)]
+    warn_on_tabs: bool,
+
+    /// Language permitted by --enforce (repeatable)
+    #[arg(long = "allow-lang", value_name = "LANG")]
+    allow_lang: Vec<String>,
+
+    /// Fail if a fenced block has no language or one outside --allow-lang
+    #[arg(long = "enforce", action = ArgAction::SetTrue)]
+    enforce: bool,
+
     /// Separator between blocks when printing multiple
     #[arg(long = "sep", default_value = "\n", value_name = "SEPARATOR")]
     separator: String,
 
+    /// Print each block's bytes exactly as in the source (line endings and trailing blank lines kept), back to back
+    #[arg(long = "print-raw-bytes", visible_alias = "raw", action = ArgAction::SetTrue)]
+    print_raw_bytes: bool,
+
+    /// Line printed between blocks from different files ({source} expands to the next file)
+    #[arg(long = "file-separator", value_name = "SEPARATOR")]
+    file_separator: Option<String>,
+
+    /// Leave blocks whose body is empty after trimming out of raw output, separators and banners included
+    #[arg(long = "skip-empty-output", action = ArgAction::SetTrue)]
+    skip_empty_output: bool,
+
     /// Preserve fences around output blocks
     #[arg(long = "fenced", action = ArgAction::SetTrue)]
     fenced: bool,
 
+    /// Delimiter for --fenced output, widened past runs of it in the content
+    #[arg(
+        long = "fence-char",
+        value_enum,
+        value_name = "CHAR",
+        default_value = "backtick"
+    )]
+    fence_char: FenceChar,
+
+    /// Keep the original opening and closing fence lines around each block
+    #[arg(long = "show-fences", action = ArgAction::SetTrue, conflicts_with = "fenced")]
+    show_fences: bool,
+
+    /// Start each fenced block with a comment naming its source file and line
+    #[arg(long = "source-comment", action = ArgAction::SetTrue, requires = "fenced")]
+    source_comment: bool,
+
+    /// Restore trailing blank lines when re-fencing blocks
+    #[arg(long = "preserve-trailing", action = ArgAction::SetTrue, requires = "fenced")]
+    preserve_trailing: bool,
+
+    /// Always end raw output with a newline, whatever the separator
+    #[arg(long = "trailing-newline", action = ArgAction::SetTrue)]
+    trailing_newline: bool,
+
+    /// Never add a newline after the last block of raw output
+    #[arg(
+        long = "no-trailing-newline",
+        action = ArgAction::SetTrue,
+        conflicts_with = "trailing_newline"
+    )]
+    no_trailing_newline: bool,
+
     /// Emit JSON instead of raw code
     #[arg(long = "json", action = ArgAction::SetTrue)]
     json: bool,
 
+    /// Emit the JSON array as a single compact line (implies --json)
+    #[arg(long = "json-compact", action = ArgAction::SetTrue)]
+    json_compact: bool,
+
+    /// Shape of JSON output: one array, one compact object per line, or pretty objects (implies --json)
+    #[arg(
+        long = "json-style",
+        value_enum,
+        value_name = "STYLE",
+        conflicts_with = "json_compact"
+    )]
+    json_style: Option<JsonStyle>,
+
+    /// Emit NDJSON records tagged with `type`, with warnings as `"warning"` records in document order
+    #[arg(
+        long = "ndjson-warnings",
+        action = ArgAction::SetTrue,
+        conflicts_with_all = ["json_compact", "json_style", "json_envelope"]
+    )]
+    ndjson_warnings: bool,
+
+    /// Wrap the JSON block array in an object with version, timestamp and count (implies --json)
+    #[arg(long = "json-envelope", action = ArgAction::SetTrue, conflicts_with = "json_style")]
+    json_envelope: bool,
+
+    /// Deterministic output: no timestamp in --json-envelope, `/` separators in sources
+    #[arg(long = "reproducible", action = ArgAction::SetTrue)]
+    reproducible: bool,
+
     /// List blocks with metadata
     #[arg(long = "list", action = ArgAction::SetTrue)]
     list: bool,
 
+    /// Template for --list lines: {index}, {source}, {lang}, {kind}, {lines}, {start_line}, {end_line}, {heading}, {hash}
+    #[arg(long = "list-format", value_name = "TEMPLATE")]
+    list_format: Option<String>,
+
+    /// With language listing, exit nonzero when no languages are found
+    #[arg(long = "fail-on-empty", action = ArgAction::SetTrue)]
+    fail_on_empty: bool,
+
+    /// Treat matching no blocks as success: exit 0 with empty output
+    #[arg(
+        long = "allow-empty",
+        alias = "quiet-empty",
+        action = ArgAction::SetTrue,
+        conflicts_with = "fail_on_empty"
+    )]
+    allow_empty: bool,
+
+    /// Print block and line counts per language
+    #[arg(long = "stats", action = ArgAction::SetTrue)]
+    stats: bool,
+
+    /// Print one JSON object summarizing the run (same as --stats --json)
+    #[arg(long = "summary-json", action = ArgAction::SetTrue)]
+    summary_json: bool,
+
+    /// List each input read and how many blocks it contributed
+    #[arg(long = "list-sources", action = ArgAction::SetTrue)]
+    list_sources: bool,
+
+    /// Print how global indices (-n) relate to per-source positions, then exit
+    #[arg(long = "print-index-map", action = ArgAction::SetTrue)]
+    print_index_map: bool,
+
+    /// Group blocks into per-language sections
+    #[arg(long = "collate", action = ArgAction::SetTrue)]
+    collate: bool,
+
+    /// Show each block's header plus its first and last N lines (default 1)
+    #[arg(
+        long = "peek",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "1",
+        value_name = "N"
+    )]
+    peek: Option<usize>,
+
+    /// Truncate --list, --peek and banner lines to COLS (default: terminal width when interactive)
+    #[arg(long = "preview-width", value_name = "COLS")]
+    preview_width: Option<usize>,
+
+    /// Show each block's enclosing heading path in --list, --peek and JSON output
+    #[arg(long = "context-headings", action = ArgAction::SetTrue)]
+    context_headings: bool,
+
+    /// Include N lines of surrounding prose on each side of a block, stopping at other fences
+    #[arg(long = "context", value_name = "N")]
+    context: Option<usize>,
+
+    /// Lines of prose to include before each block (overrides --context)
+    #[arg(long = "before", value_name = "N")]
+    before: Option<usize>,
+
+    /// Lines of prose to include after each block (overrides --context)
+    #[arg(long = "after", value_name = "N")]
+    after: Option<usize>,
+
+    /// Add derived lines, chars, bytes, is_empty and canonical_lang fields to JSON blocks
+    #[arg(long = "annotate", action = ArgAction::SetTrue)]
+    annotate: bool,
+
+    /// Add a position-independent block_id (from source and first line) to JSON blocks
+    #[arg(long = "block-ids", action = ArgAction::SetTrue)]
+    block_ids: bool,
+
+    /// Emit a Markdown document of the selected blocks under their headings
+    #[arg(long = "to-markdown", action = ArgAction::SetTrue)]
+    to_markdown: bool,
+
+    /// List the imports of Rust, Python and JavaScript blocks (per block with --json)
+    #[arg(long = "imports", alias = "extract-imports", action = ArgAction::SetTrue)]
+    imports: bool,
+
     /// Include inline code spans (backticks)
     #[arg(long = "inline", action = ArgAction::SetTrue)]
     inline: bool,
 
+    /// Report backtick runs and the spans they form on stderr, line by line (implies --inline)
+    #[arg(long = "inline-debug", alias = "count-backticks", action = ArgAction::SetTrue)]
+    inline_debug: bool,
+
+    /// Recognize fences inside `>` blockquotes and strip the marker from their content
+    #[arg(long = "blockquotes", action = ArgAction::SetTrue)]
+    blockquotes: bool,
+
+    /// Parse every input as FORMAT instead of detecting it from the extension
+    #[arg(long = "format", value_enum, value_name = "FORMAT")]
+    format: Option<InputFormat>,
+
+    /// Read inputs as Rust source and extract the code blocks in their `///` and `//!` docs
+    #[arg(long = "rustdoc", action = ArgAction::SetTrue, conflicts_with = "format")]
+    rustdoc: bool,
+
+    /// Extract inline code from GitHub-style table cells, recording `row` and `col`
+    #[arg(long = "tables", action = ArgAction::SetTrue)]
+    tables: bool,
+
+    /// Extract the lines between literal OPEN and CLOSE delimiter lines instead of parsing fences
+    #[arg(
+        long = "between",
+        num_args = 2,
+        value_names = ["OPEN", "CLOSE"],
+        value_parser = parse_delimiter,
+        conflicts_with = "format"
+    )]
+    between: Option<Vec<String>>,
+
+    /// Treat non-Markdown inputs as one code block, taking the language from the extension
+    #[arg(long = "as-code", action = ArgAction::SetTrue)]
+    as_code: bool,
+
+    /// Drop blocks that are empty or contain only whitespace
+    #[arg(long = "skip-blank-blocks", action = ArgAction::SetTrue)]
+    skip_blank_blocks: bool,
+
+    /// Remove comments from blocks in known languages (heuristic, not a parser)
+    #[arg(long = "strip-comments", action = ArgAction::SetTrue)]
+    strip_comments: bool,
+
+    /// Pipe each block through CMD (run by `sh -c`) and use its stdout as the code
+    #[arg(long = "transform", value_name = "CMD")]
+    transform: Option<String>,
+
+    /// Pipe blocks of LANG through CMD, taking precedence over --transform (repeatable)
+    #[arg(long = "transform-lang", value_name = "LANG=CMD")]
+    transform_lang: Vec<String>,
+
+    /// Fail when a transform exits nonzero instead of keeping the original block
+    #[arg(long = "transform-strict", action = ArgAction::SetTrue)]
+    transform_strict: bool,
+
+    /// Drop one blank line directly after the opening fence, shifting start lines
+    #[arg(
+        long = "strip-leading-blank",
+        alias = "trim-common-leading-blank",
+        action = ArgAction::SetTrue
+    )]
+    strip_leading_blank: bool,
+
+    /// Remove the whitespace prefix shared by every non-blank line of a block
+    #[arg(long = "dedent", action = ArgAction::SetTrue)]
+    dedent: bool,
+
+    /// Indent every non-blank line of raw output by N spaces, e.g. to nest it in a list item
+    #[arg(long = "indent", value_name = "N")]
+    indent: Option<usize>,
+
+    /// Separate `$`/`#` prompt commands from output in console/shell-session blocks
+    #[arg(long = "split-prompts", action = ArgAction::SetTrue)]
+    split_prompts: bool,
+
+    /// Strip a leading prompt (one of CHARS plus a space; default `$` and `#`) from shell block lines; `#` only in console sessions
+    #[arg(
+        long = "strip-prompt",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "$#",
+        value_name = "CHARS"
+    )]
+    strip_prompt: Option<String>,
+
+    /// Turn doc examples into runnable code: unhide `# ` lines in Rust, strip `>>>`/`...` prompts in Python
+    #[arg(long = "doctest-strip", action = ArgAction::SetTrue)]
+    doctest_strip: bool,
+
+    /// Print only the commands of shell session blocks (with --split-prompts)
+    #[arg(long = "commands-only", action = ArgAction::SetTrue, requires = "split_prompts")]
+    commands_only: bool,
+
     /// Include source line numbers in output
     #[arg(long = "line-numbers", action = ArgAction::SetTrue)]
     line_numbers: bool,
 
+    /// Start the line-number gutter at N for every block instead of the source line
+    #[arg(long = "number-start", value_name = "N", requires = "line_numbers")]
+    number_start: Option<usize>,
+
+    /// Fixed width for the line-number gutter (default: fit the largest line number)
+    #[arg(long = "gutter-width", value_name = "N", requires = "line_numbers")]
+    gutter_width: Option<usize>,
+
+    /// Number only these block-relative lines (e.g. `2,4-6`), leaving blank gutters elsewhere
+    #[arg(
+        long = "number-lines",
+        value_name = "RANGES",
+        requires = "line_numbers"
+    )]
+    number_lines: Option<String>,
+
+    /// Compare blocks with those of another document and print a diff of changed bodies
+    #[arg(long = "diff", value_name = "FILE", value_hint = ValueHint::FilePath)]
+    diff: Option<PathBuf>,
+
+    /// How blocks are paired with --diff
+    #[arg(
+        long = "diff-key",
+        value_enum,
+        default_value = "index",
+        requires = "diff"
+    )]
+    diff_key: DiffKey,
+
+    /// Write each block to a path built from {source_dir}, {source_stem}, {index}, {lang}, {ext}, {section}
+    #[arg(long = "output-template", value_name = "TEMPLATE")]
+    output_template: Option<String>,
+
+    /// Group blocks by their enclosing heading of LEVEL (default 1) under section markers
+    #[arg(
+        long = "split-on-heading",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "1",
+        value_name = "LEVEL"
+    )]
+    split_on_heading: Option<usize>,
+
+    /// Concatenate the selected blocks into FILE, e.g. to assemble a runnable script
+    #[arg(
+        long = "merge-into",
+        value_name = "FILE",
+        conflicts_with = "output_template"
+    )]
+    merge_into: Option<PathBuf>,
+
+    /// Line(s) written before the merged blocks, such as a shebang
+    #[arg(long = "prepend", value_name = "TEXT", requires = "merge_into")]
+    prepend: Option<String>,
+
+    /// Line(s) written after the merged blocks
+    #[arg(long = "append", value_name = "TEXT", requires = "merge_into")]
+    append: Option<String>,
+
+    /// Let --merge-into combine blocks of different languages
+    #[arg(long = "allow-mixed", action = ArgAction::SetTrue, requires = "merge_into")]
+    allow_mixed: bool,
+
+    /// Stream blocks as JSON Lines into FILE, one object per block
+    #[arg(
+        long = "output-json-lines-to",
+        value_name = "FILE",
+        conflicts_with = "output_template"
+    )]
+    output_json_lines_to: Option<PathBuf>,
+
+    /// Also write a JSON map from each emitted block (or output file) to its origin
+    #[arg(long = "source-map", visible_alias = "manifest", value_name = "FILE")]
+    source_map: Option<PathBuf>,
+
+    /// Allow several blocks to resolve to the same output path (last one wins)
+    #[arg(long = "force", action = ArgAction::SetTrue)]
+    force: bool,
+
+    /// Print the paths and sizes file-writing options would produce without writing them
+    #[arg(long = "dry-run", action = ArgAction::SetTrue)]
+    dry_run: bool,
+
+    /// Write the blocks as a tar archive to FILE (default stdout), named by --output-template
+    #[arg(
+        long = "tar",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "-",
+        value_name = "FILE",
+        conflicts_with_all = ["json", "json_compact", "json_style", "json_envelope", "list", "stats", "peek", "to_markdown", "merge_into"]
+    )]
+    tar: Option<PathBuf>,
+
+    /// Gzip the --tar archive
+    #[arg(long = "tar-gz", action = ArgAction::SetTrue, requires = "tar")]
+    tar_gz: bool,
+
+    /// Escape each block as a string literal for TARGET in raw output
+    #[arg(long = "escape-for", value_enum, value_name = "TARGET")]
+    escape_for: Option<EscapeTarget>,
+
+    /// Keep sources in reading order but sort the blocks inside each by KEY
+    #[arg(
+        long = "sort-within-source",
+        alias = "sort-within",
+        value_enum,
+        value_name = "KEY"
+    )]
+    sort_within_source: Option<SortKey>,
+
+    /// Process inputs last-to-first; stdin, normally first, then comes last
+    #[arg(long = "reverse-files", action = ArgAction::SetTrue)]
+    reverse_files: bool,
+
+    /// Stop after writing N bytes to stdout, with a notice on stderr and exit status 3
+    #[arg(long = "max-output-bytes", value_name = "N")]
+    max_output_bytes: Option<usize>,
+
+    /// Report unreadable inputs on stderr and carry on with the rest, exiting nonzero at the end
+    #[arg(long = "keep-going", action = ArgAction::SetTrue)]
+    keep_going: bool,
+
+    /// Show a progress bar on stderr while reading many files
+    #[arg(long = "progress", action = ArgAction::SetTrue)]
+    progress: bool,
+
+    /// Print the JSON Schema of --json output and exit
+    #[arg(long = "print-schema", action = ArgAction::SetTrue)]
+    print_schema: bool,
+
+    /// Print the version, input formats and compiled-in features as JSON and exit
+    #[arg(long = "version-json", action = ArgAction::SetTrue)]
+    version_json: bool,
+
+    /// Ignore .mdcoderc and the user config file
+    #[arg(long = "no-config", action = ArgAction::SetTrue)]
+    no_config: bool,
+
+    /// Decode inputs with this character encoding (e.g. latin1, shift_jis)
+    #[arg(long = "encoding", value_name = "LABEL", default_value = "utf-8")]
+    encoding: String,
+
+    /// Guess each input's character encoding instead of using --encoding
+    #[arg(
+        long = "encoding-detect",
+        action = ArgAction::SetTrue,
+        conflicts_with = "encoding"
+    )]
+    encoding_detect: bool,
+
+    /// Expand directory inputs to the Markdown, reST, AsciiDoc and notebook files below them
+    #[arg(short = 'r', long = "recursive", action = ArgAction::SetTrue)]
+    recursive: bool,
+
+    /// Descend into symlinked directories during -r scans (cycles are skipped)
+    #[arg(long = "follow-symlinks", action = ArgAction::SetTrue, requires = "recursive")]
+    follow_symlinks: bool,
+
+    /// Report per-input details, such as detected encodings, on stderr
+    #[arg(long = "verbose", action = ArgAction::SetTrue)]
+    verbose: bool,
+
     /// Input files; if omitted, read from stdin. When both are provided, stdin is processed first.
     #[arg(value_name = "FILE", value_hint = ValueHint::FilePath)]
     files: Vec<PathBuf>,
+
+    /// Reuse parses of unchanged inputs from an on-disk cache
+    #[arg(long = "cache", action = ArgAction::SetTrue)]
+    cache: bool,
+
+    /// Cache directory (implies --cache; default $XDG_CACHE_HOME/mdcode)
+    #[arg(long = "cache-dir", value_name = "DIR", value_hint = ValueHint::DirPath)]
+    cache_dir: Option<PathBuf>,
+
+    /// Parse every input afresh, overriding --cache and --cache-dir
+    #[arg(long = "no-cache", action = ArgAction::SetTrue)]
+    no_cache: bool,
+
+    /// Match input and --in-source globs case-insensitively
+    #[arg(long = "glob-case-insensitive", action = ArgAction::SetTrue)]
+    glob_case_insensitive: bool,
+
+    /// Fetch http:// and https:// inputs (requires the `reqwest` feature)
+    #[arg(long = "allow-remote", action = ArgAction::SetTrue)]
+    allow_remote: bool,
+}
+
+impl Args {
+    fn wants_json(&self) -> bool {
+        self.json || self.json_compact || self.json_style.is_some() || self.json_envelope
+    }
+
+    /// Context lines wanted before and after each block.
+    fn context_lines(&self) -> (usize, usize) {
+        let both = self.context.unwrap_or(0);
+        (self.before.unwrap_or(both), self.after.unwrap_or(both))
+    }
+
+    /// The `--preview-width`, falling back to the terminal width (or 80) only
+    /// when stdout is a terminal so piped listings stay complete.
+    fn preview_width(&self) -> Option<usize> {
+        self.preview_width.or_else(|| {
+            let term = console::Term::stdout();
+            term.is_term()
+                .then(|| term.size_checked().map_or(80, |(_, cols)| cols as usize))
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+enum JsonStyle {
+    /// A single array of blocks
+    #[default]
+    Array,
+    /// One compact object per line
+    Ndjson,
+    /// Pretty-printed objects separated by blank lines, without an array
+    Objects,
+}
+
+/// `--version-json` output, for wrappers probing what this build supports.
+#[derive(Debug, Serialize)]
+struct VersionInfo {
+    version: &'static str,
+    /// Values accepted by `--format`.
+    formats: Vec<String>,
+    /// Output modes and capabilities, optional cargo features included only
+    /// when compiled in.
+    features: Vec<&'static str>,
+}
+
+impl VersionInfo {
+    fn current() -> Self {
+        let mut features = vec![
+            "inline",
+            "tables",
+            "blockquotes",
+            "json",
+            "ndjson",
+            "list",
+            "stats",
+            "diff",
+            "tar",
+            "cache",
+        ];
+        if cfg!(feature = "reqwest") {
+            features.push("remote");
+        }
+        VersionInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            formats: InputFormat::value_variants()
+                .iter()
+                .filter_map(|f| f.to_possible_value())
+                .map(|v| v.get_name().to_string())
+                .collect(),
+            features,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+enum InputFormat {
+    Markdown,
+    /// reStructuredText `code-block` directives and `::` literal blocks
+    Rst,
+    /// AsciiDoc `[source,LANG]` listings and `----` blocks
+    Adoc,
+    /// Jupyter notebook code cells
+    Ipynb,
+    /// Code blocks in the `///` and `//!` doc comments of Rust source
+    Rustdoc,
+}
+
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+enum FenceChar {
+    #[default]
+    Backtick,
+    Tilde,
+}
+
+impl FenceChar {
+    fn as_char(self) -> char {
+        match self {
+            FenceChar::Backtick => '`',
+            FenceChar::Tilde => '~',
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+enum ColorChoice {
+    /// Color when stdout is a terminal and NO_COLOR is unset
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Auto => io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none(),
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum EscapeTarget {
+    /// A single-quoted POSIX shell word
+    Shell,
+    /// A JSON string literal
+    Json,
+    /// A C string literal
+    C,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum SortKey {
+    /// Alphabetically by language, unlabeled blocks last
+    Lang,
+    /// By line count, shortest first
+    Size,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum DiffKey {
+    /// Pair the Nth block of each document
+    Index,
+    /// Pair the Nth block of each language
+    #[value(name = "lang+position")]
+    LangPosition,
 }
 
 #[derive(Debug)]
 enum LangSelector {
     All,
     List,
-    Filter(String),
+    /// Keep blocks matching any of these languages.
+    Filter(Vec<String>),
 }
 
-#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, JsonSchema, PartialEq)]
 #[serde(rename_all = "lowercase")]
 enum BlockKind {
+    #[default]
     Fenced,
     Inline,
 }
 
+impl BlockKind {
+    fn name(self) -> &'static str {
+        match self {
+            BlockKind::Fenced => "fenced",
+            BlockKind::Inline => "inline",
+        }
+    }
+}
+
 #[derive(Debug)]
 struct InputSource {
     name: String,
     content: String,
 }
 
-#[derive(Debug, Serialize)]
+/// Serialized whole, every field included, for the `--cache` entries.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
 struct CodeBlock {
     index: usize,
     source: String,
     kind: BlockKind,
     lang: Option<String>,
+    /// Attributes from the info string (`key=value`, `.class`, `#id`).
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    attrs: BTreeMap<String, String>,
+    /// Leading whitespace before the opening fence, stripped from each content line.
+    indent: usize,
     #[serde(skip_serializing_if = "Option::is_none")]
     start_line: Option<usize>,
     #[serde(skip_serializing_if = "Option::is_none")]
     end_line: Option<usize>,
+    /// Byte span of the raw content in the source, fences excluded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    byte_start: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    byte_end: Option<usize>,
     code: String,
+    /// Blank lines trimmed from the end of `code`.
+    trailing_newlines: usize,
+    /// Enclosing ATX headings, outermost first.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    heading_path: Vec<String>,
+    /// Level of each entry in `heading_path`.
+    heading_levels: Vec<usize>,
+    /// `--split-on-heading` section; `None` before the first such heading.
+    section: Option<String>,
+    /// Prompt-stripped command lines of a shell session block.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    commands: Option<Vec<String>>,
+    /// Line of the opening fence itself.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fence_start_line: Option<usize>,
+    /// Line of the closing fence; `None` when the fence is unterminated.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fence_end_line: Option<usize>,
+    /// False only for a fence that ran to the end of its file or blockquote.
+    closed: bool,
+    /// Hex SHA-256 of the code as parsed, before any transforms.
+    hash: String,
+    /// Position-independent `--block-ids` identifier; see `block_id`.
+    block_id: String,
+    /// `code`, `diagram`, `math` or a configured category.
+    category: String,
+    /// Delimiter of a Markdown fence: '`' or '~'.
+    fence_char: Option<char>,
+    /// Length of a Markdown fence's opening delimiter run.
+    fence_len: Option<usize>,
+    /// Table row of a `--tables` span: 0 for the header, body rows from 1.
+    row: Option<usize>,
+    /// Table column of a `--tables` span, from 0.
+    col: Option<usize>,
+    /// Prose lines just before the block (`--context`, `--before`).
+    context_before: Vec<String>,
+    /// Prose lines just after the block (`--context`, `--after`).
+    context_after: Vec<String>,
+    /// Opening and closing fence lines exactly as written.
+    fence_open: Option<String>,
+    fence_close: Option<String>,
+}
+
+impl CodeBlock {
+    /// The nearest enclosing heading.
+    fn heading(&self) -> Option<&str> {
+        self.heading_path.last().map(String::as_str)
+    }
+
+    /// The enclosing heading of exactly `level`, if any.
+    fn heading_at(&self, level: usize) -> Option<&str> {
+        self.heading_levels
+            .iter()
+            .position(|l| *l == level)
+            .map(|i| self.heading_path[i].as_str())
+    }
 }
 
 #[derive(Debug)]
@@ -89,521 +936,6993 @@ enum IndexFilter {
     Range { start: usize, end: usize },
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
-    let lang_selector = parse_lang_selector(&args.lang);
-
-    let inputs = collect_inputs(&args)?;
-    if inputs.is_empty() {
-        eprintln!("No input provided. Pass files or pipe markdown into stdin.");
-        std::process::exit(1);
-    }
+/// Defaults loaded from `.mdcoderc` or `$XDG_CONFIG_HOME/mdcode/config.toml`.
+/// Values given on the command line always win.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    inline: Option<bool>,
+    fenced: Option<bool>,
+    line_numbers: Option<bool>,
+    separator: Option<String>,
+    lang: Option<String>,
+    /// Alternate language names, e.g. `rs = "rust"`.
+    aliases: BTreeMap<String, String>,
+    /// Language categories, e.g. `plantuml = "diagram"`.
+    categories: BTreeMap<String, String>,
+}
 
-    let mut blocks = collect_blocks(inputs, args.inline);
-    if let LangSelector::Filter(lang) = &lang_selector {
-        blocks.retain(|b| matches_lang(b, lang));
-    }
+impl Config {
+    fn apply(&self, args: &mut Args, matches: &ArgMatches) {
+        let from_cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
 
-    if let Some(filter) = parse_index_filter(args.number.as_deref())? {
-        blocks = apply_index_filter(blocks, filter);
+        if !from_cli("inline") {
+            args.inline = self.inline.unwrap_or(args.inline);
+        }
+        if !from_cli("fenced") {
+            args.fenced = self.fenced.unwrap_or(args.fenced);
+        }
+        if !from_cli("line_numbers") {
+            args.line_numbers = self.line_numbers.unwrap_or(args.line_numbers);
+        }
+        if !from_cli("separator")
+            && let Some(separator) = &self.separator
+        {
+            args.separator = separator.clone();
+        }
+        if !from_cli("lang")
+            && args.lang.is_none()
+            && let Some(lang) = &self.lang
+        {
+            args.lang = Some(Some(lang.clone()));
+        }
     }
+}
 
-    if blocks.is_empty() {
-        eprintln!("No matching code blocks found.");
-        std::process::exit(1);
+fn config_path() -> Option<PathBuf> {
+    let local = PathBuf::from(".mdcoderc");
+    if local.is_file() {
+        return Some(local);
     }
 
-    if let LangSelector::List = lang_selector {
-        list_languages(&blocks);
-        return Ok(());
-    }
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    let user = config_home.join("mdcode").join("config.toml");
+    user.is_file().then_some(user)
+}
 
-    if args.json {
-        emit_json(&blocks, args.line_numbers)?;
-        return Ok(());
-    }
+fn load_config() -> Result<Config, Box<dyn std::error::Error>> {
+    let Some(path) = config_path() else {
+        return Ok(Config::default());
+    };
 
-    if args.list {
-        print_list(&blocks, args.line_numbers);
-        return Ok(());
-    }
+    let raw = fs::read_to_string(&path)?;
+    toml::from_str(&raw).map_err(|e| format!("invalid config {}: {e}", path.display()).into())
+}
 
-    print_raw(&blocks, args.fenced, args.line_numbers, &args.separator);
-    Ok(())
+fn parse_cli() -> (Args, ArgMatches) {
+    split_cli(Cli::command().get_matches())
 }
 
-fn parse_lang_selector(arg: &Option<Option<String>>) -> LangSelector {
-    match arg {
-        None => LangSelector::All,
-        Some(None) => LangSelector::List,
-        Some(Some(lang)) => LangSelector::Filter(lang.to_lowercase()),
-    }
+/// Resolves parsed matches into the effective arguments and the matches
+/// their value sources are read from.
+fn split_cli(matches: ArgMatches) -> (Args, ArgMatches) {
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let arg_matches = match matches.subcommand() {
+        Some((_, sub_matches)) => sub_matches.clone(),
+        None => matches,
+    };
+
+    let args = match cli.command {
+        Some(command) => command.into_args(),
+        None => cli.args,
+    };
+    (args, arg_matches)
 }
 
-fn collect_inputs(args: &Args) -> Result<Vec<InputSource>, Box<dyn std::error::Error>> {
-    let mut sources = Vec::new();
-    let mut read_stdin = !io::stdin().is_terminal();
-    if args.files.is_empty() {
-        read_stdin = true;
-    }
+/// Exit status when `--max-output-bytes` cut the output short.
+const TRUNCATED_EXIT: i32 = 3;
 
-    if read_stdin {
-        let mut buffer = String::new();
-        io::stdin().read_to_string(&mut buffer)?;
-        if !buffer.is_empty() || args.files.is_empty() {
-            sources.push(InputSource {
-                name: "stdin".to_string(),
-                content: buffer,
-            });
+/// A writer that passes at most `max` bytes on to `inner` and drops the rest.
+struct Capped<W> {
+    inner: W,
+    max: usize,
+    written: usize,
+    truncated: bool,
+}
+
+impl<W: Write> Capped<W> {
+    fn new(inner: W, max: usize) -> Self {
+        Capped {
+            inner,
+            max,
+            written: 0,
+            truncated: false,
         }
     }
 
-    for path in &args.files {
-        let content = fs::read_to_string(path)?;
-        sources.push(InputSource {
-            name: path.display().to_string(),
-            content,
-        });
+    /// The exit status the output so far calls for.
+    fn status(&self) -> i32 {
+        if self.truncated { TRUNCATED_EXIT } else { 0 }
     }
-
-    Ok(sources)
 }
 
-fn collect_blocks(inputs: Vec<InputSource>, include_inline: bool) -> Vec<CodeBlock> {
-    let mut blocks = Vec::new();
-    for input in inputs {
-        let mut parsed = parse_blocks(&input, include_inline);
-        blocks.append(&mut parsed);
+impl<W: Write> Write for Capped<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let keep = buf.len().min(self.max - self.written);
+        self.inner.write_all(&buf[..keep])?;
+        self.written += keep;
+        self.truncated |= keep < buf.len();
+        Ok(buf.len())
     }
 
-    for (index, block) in blocks.iter_mut().enumerate() {
-        block.index = index;
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
     }
-
-    blocks
 }
 
-fn parse_blocks(input: &InputSource, include_inline: bool) -> Vec<CodeBlock> {
-    let mut blocks = Vec::new();
-    let mut in_fence: Option<FenceState> = None;
-    let mut last_line_no = 0usize;
+/// Stdout capped by `--max-output-bytes`, when given.
+static OUTPUT_LIMIT: Mutex<Option<Capped<io::Stdout>>> = Mutex::new(None);
 
-    for (idx, raw_line) in input.content.lines().enumerate() {
-        let line_no = idx + 1;
-        last_line_no = line_no;
+fn limit_output(limit: usize) {
+    *OUTPUT_LIMIT.lock().unwrap_or_else(PoisonError::into_inner) =
+        Some(Capped::new(io::stdout(), limit));
+}
 
-        if let Some(state) = &mut in_fence {
-            if is_closing_fence(raw_line, state.fence_char, state.fence_len) {
-                let end_line = line_no.saturating_sub(1);
-                blocks.push(CodeBlock {
-                    index: 0,
-                    source: input.name.clone(),
-                    kind: BlockKind::Fenced,
-                    lang: state.lang.clone(),
-                    start_line: Some(state.start_line),
-                    end_line: Some(end_line),
-                    code: state.buffer.trim_end_matches('\n').to_string(),
-                });
-                in_fence = None;
-            } else {
-                state.buffer.push_str(raw_line);
-                state.buffer.push('\n');
-            }
-            continue;
-        }
+/// Stdout for everything mdcode prints. Under `--max-output-bytes` it writes
+/// up to the limit, then reports the truncation and exits.
+struct Output;
 
-        if let Some((fence_char, fence_len, lang)) = parse_fence_start(raw_line) {
-            in_fence = Some(FenceState {
-                fence_char,
-                fence_len,
-                lang,
-                buffer: String::new(),
-                start_line: line_no + 1,
-            });
-            continue;
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut limit = OUTPUT_LIMIT.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some(capped) = limit.as_mut() else {
+            return io::stdout().write(buf);
+        };
+        let n = capped.write(buf)?;
+        if capped.truncated {
+            capped.flush()?;
+            eprintln!(
+                "output truncated after {} bytes (--max-output-bytes)",
+                capped.max
+            );
+            std::process::exit(capped.status());
         }
+        Ok(n)
+    }
 
-        if include_inline {
-            let mut inline_blocks = parse_inline_blocks(raw_line, line_no, &input.name);
-            blocks.append(&mut inline_blocks);
-        }
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
     }
+}
 
-    if let Some(state) = in_fence {
-        // Unterminated fence; treat rest of file as the block.
-        let end_line = if state.buffer.is_empty() {
-            last_line_no
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let (args, matches) = parse_cli();
+    let mut stdin = io::stdin();
+    let piped = (!stdin.is_terminal()).then_some(&mut stdin as &mut dyn Read);
+    let code = run(args, &matches, piped)?;
+    if code != 0 {
+        std::process::exit(code);
+    }
+    Ok(())
+}
+
+/// Runs mdcode, returning the exit status. `stdin` is the piped standard
+/// input, `None` when it is a terminal.
+fn run(
+    mut args: Args,
+    matches: &ArgMatches,
+    stdin: Option<&mut dyn Read>,
+) -> Result<i32, Box<dyn std::error::Error>> {
+    let config = if args.no_config {
+        Config::default()
+    } else {
+        load_config()?
+    };
+    config.apply(&mut args, matches);
+    if args.ndjson_warnings {
+        collect_warnings();
+    }
+    if let Some(limit) = args.max_output_bytes {
+        limit_output(limit);
+    }
+
+    if args.version_json {
+        write_json(&VersionInfo::current(), true)?;
+        return Ok(0);
+    }
+
+    if args.print_schema {
+        let schema = schemars::schema_for!(Vec<JsonBlock>);
+        serde_json::to_writer_pretty(Output, &schema)?;
+        writeln!(Output)?;
+        return Ok(0);
+    }
+
+    let (inputs, skipped) = collect_inputs(&args, stdin)?;
+    if inputs.is_empty() {
+        eprintln!("No input provided. Pass files or pipe markdown into stdin.");
+        return Ok(1);
+    }
+    let code = extract(&args, &config, inputs)?;
+    // Inputs dropped by --keep-going fail the run whatever the mode reported.
+    Ok(if skipped { code.max(1) } else { code })
+}
+
+/// Parses, filters and emits the blocks of `inputs`, returning the exit status.
+fn extract(
+    args: &Args,
+    config: &Config,
+    mut inputs: Vec<InputSource>,
+) -> Result<i32, Box<dyn std::error::Error>> {
+    let file_langs = args.lang_file.as_deref().map(read_lang_file).transpose()?;
+    let lang_selector = match &file_langs {
+        Some(langs) => LangSelector::Filter(langs.clone()),
+        None => parse_lang_selector(&args.lang),
+    };
+
+    if args.reproducible {
+        for input in &mut inputs {
+            input.name = portable_name(&input.name);
+        }
+    }
+    let source_names: Vec<String> = inputs.iter().map(|i| i.name.clone()).collect();
+    let (before, after) = args.context_lines();
+    // Byte spans are narrowed against the source when the code is cut down.
+    let narrows_spans = args.strip_leading_blank || args.region.is_some() || args.lines.is_some();
+    let raw_sources: BTreeMap<String, String> =
+        if args.print_raw_bytes || before + after > 0 || narrows_spans {
+            inputs
+                .iter()
+                .map(|i| (i.name.clone(), i.content.clone()))
+                .collect()
         } else {
-            last_line_no
+            BTreeMap::new()
         };
-        blocks.push(CodeBlock {
-            index: 0,
-            source: input.name.clone(),
-            kind: BlockKind::Fenced,
-            lang: state.lang,
-            start_line: Some(state.start_line),
-            end_line: Some(end_line),
-            code: state.buffer.trim_end_matches('\n').to_string(),
+    let parse = ParseOptions {
+        include_inline: args.inline || args.inline_debug,
+        inline_debug: args.inline_debug,
+        format: args.format.or(args.rustdoc.then_some(InputFormat::Rustdoc)),
+        as_code: args.as_code,
+        blockquotes: args.blockquotes,
+        tables: args.tables,
+        between: args
+            .between
+            .as_deref()
+            .map(|pair| (pair[0].clone(), pair[1].clone())),
+        cache_dir: if args.no_cache {
+            None
+        } else {
+            args.cache_dir
+                .clone()
+                .or_else(|| args.cache.then(default_cache_dir))
+        },
+    };
+    let mut blocks = collect_blocks(inputs, &parse);
+    // Taken before filtering so --join-adjacent can tell a gap left by an
+    // inline span from one left by a dropped block.
+    let inline_spans: BTreeSet<usize> = blocks
+        .iter()
+        .filter(|b| b.kind == BlockKind::Inline)
+        .map(|b| b.index)
+        .collect();
+    if before + after > 0 {
+        attach_context(&mut blocks, &raw_sources, before, after);
+    }
+    let mut categories = config.categories.clone();
+    for mapping in &args.kind_from_lang {
+        let (lang, category) = mapping.split_once('=').ok_or_else(|| {
+            format!("invalid --kind-from-lang '{mapping}', expected LANG=CATEGORY")
+        })?;
+        categories.insert(lang.to_lowercase(), category.to_lowercase());
+    }
+    for block in &mut blocks {
+        block.category = block_category(block, &categories, &config.aliases);
+        if let Some(level) = args.split_on_heading {
+            block.section = block.heading_at(level).map(str::to_string);
+        }
+    }
+    if args.enforce {
+        // A --lang-file doubles as the allowlist so CI filters and checks alike.
+        let mut allowed = args.allow_lang.clone();
+        allowed.extend(file_langs.iter().flatten().cloned());
+        let violations = lang_violations(&blocks, &allowed, &config.aliases);
+        if !violations.is_empty() {
+            for violation in &violations {
+                eprintln!("{violation}");
+            }
+            return Ok(1);
+        }
+    }
+
+    let mut trace = args.explain.then(|| FilterTrace::new(&blocks));
+    if let LangSelector::Filter(langs) = &lang_selector {
+        blocks.retain(|b| langs.iter().any(|l| matches_lang(b, l, &config.aliases)));
+        explain_step(&mut trace, "--lang", &blocks);
+    }
+    if args.skip_blank_blocks {
+        blocks.retain(|b| !is_blank_block(b));
+        explain_step(&mut trace, "--skip-blank-blocks", &blocks);
+    }
+    if args.unclosed_only {
+        blocks.retain(|b| !b.closed);
+        explain_step(&mut trace, "--unclosed-only", &blocks);
+    }
+    let grep = args.grep.as_deref().map(Regex::new).transpose()?;
+    if let Some(pattern) = &grep {
+        blocks.retain(|b| pattern.is_match(&b.code));
+        explain_step(&mut trace, "--grep", &blocks);
+    }
+    if !args.attr.is_empty() {
+        blocks.retain(|b| args.attr.iter().all(|f| matches_attr(b, f)));
+        explain_step(&mut trace, "--attr", &blocks);
+    }
+    if !args.category.is_empty() {
+        blocks.retain(|b| {
+            args.category
+                .iter()
+                .any(|c| c.eq_ignore_ascii_case(&b.category))
         });
+        explain_step(&mut trace, "--category", &blocks);
+    }
+    if let Some(raw) = &args.where_expr {
+        let expr = parse_where(raw).map_err(|e| format!("--where: {e}"))?;
+        blocks.retain(|b| expr.matches(b, &config.aliases));
+        explain_step(&mut trace, "--where", &blocks);
+    }
+    if let Some(limit) = args.max_per_source {
+        blocks = cap_per_source(blocks, limit);
+        explain_step(&mut trace, "--max-per-source", &blocks);
+    }
+    if let Some(prefix) = &args.hash_select {
+        blocks = select_by_hash(blocks, prefix)?;
+        explain_step(&mut trace, "--hash-select", &blocks);
+    }
+    if args.join_adjacent {
+        blocks = join_adjacent(blocks, &inline_spans);
+        explain_step(&mut trace, "--join-adjacent", &blocks);
+    }
+    if let Some(name) = &args.in_source {
+        let matcher = source_matcher(name, args.glob_case_insensitive)?;
+        blocks.retain(|b| matcher(&b.source));
+        explain_step(&mut trace, "--in-source", &blocks);
+        if let Some(trace) = &mut trace {
+            trace.renumber(&blocks);
+        }
+        for (index, block) in blocks.iter_mut().enumerate() {
+            block.index = index;
+        }
+    }
+    if let Some(line) = args.at_line {
+        blocks.retain(|b| contains_line(b, line));
+        explain_step(&mut trace, "--at-line", &blocks);
+        if blocks.is_empty() {
+            if let Some(trace) = &trace {
+                trace.report();
+            }
+            eprintln!("line {line} is not inside a code block");
+            return Ok(1);
+        }
+    }
+    if args.first_per_lang {
+        blocks = first_per_lang(blocks, &config.aliases);
+        explain_step(&mut trace, "--first-per-lang", &blocks);
+    }
+    if let Some(n) = args.sample {
+        blocks = sample_blocks(blocks, n, args.seed);
+        explain_step(&mut trace, "--sample", &blocks);
     }
 
-    blocks
+    if let Some(other_path) = &args.diff {
+        let other = read_file(other_path, Charset::from_args(args)?)?;
+        let mut other_blocks = collect_blocks(vec![other], &parse);
+        if let LangSelector::Filter(langs) = &lang_selector {
+            other_blocks.retain(|b| langs.iter().any(|l| matches_lang(b, l, &config.aliases)));
+        }
+
+        let report = diff_blocks(&blocks, &other_blocks, args.diff_key);
+        write!(Output, "{report}")?;
+        return Ok(if report.is_empty() { 0 } else { 1 });
+    }
+
+    if let Some(filter) = parse_index_filter(args.number.as_deref())? {
+        if args.lines.is_some() && !matches!(filter, IndexFilter::Single(_)) {
+            return Err("--lines requires -n to select a single block".into());
+        }
+        blocks = apply_index_filter(blocks, filter);
+        explain_step(&mut trace, "-n", &blocks);
+    }
+
+    if args.list_sources {
+        for (name, count) in source_counts(&source_names, &blocks) {
+            writeln!(Output, "{name} ({count} blocks)")?;
+        }
+        return Ok(0);
+    }
+
+    if args.print_index_map {
+        write!(Output, "{}", render_index_map(&blocks))?;
+        return Ok(0);
+    }
+
+    if let Some(raw) = &args.lines {
+        let (start, end) = parse_line_range(raw)?;
+        for block in &mut blocks {
+            let source = raw_sources.get(&block.source).map(String::as_str);
+            slice_lines(block, source, start, end);
+        }
+    }
+
+    if let Some(name) = &args.region {
+        blocks.retain_mut(|b| {
+            let source = raw_sources.get(&b.source).map(String::as_str);
+            extract_region(b, source, name)
+        });
+        explain_step(&mut trace, "--region", &blocks);
+    }
+    if let Some(trace) = &trace {
+        trace.report();
+    }
+    if let Some(name) = &args.region
+        && blocks.is_empty()
+        && !args.allow_empty
+    {
+        eprintln!("no block contains region '{name}'");
+        return Ok(1);
+    }
+
+    if args.warn_mixed_fences
+        || args.require_fence_char.is_some()
+        || args.require_fence_len.is_some()
+        || args.warn_on_tabs
+    {
+        let mut violations = Vec::new();
+        if args.warn_mixed_fences || args.require_fence_char.is_some() {
+            let required = args.require_fence_char.map(FenceChar::as_char);
+            violations.extend(mixed_fences(&blocks, required));
+        }
+        if let Some(len) = args.require_fence_len {
+            violations.extend(wrong_fence_lengths(&blocks, usize::try_from(len)?));
+        }
+        if args.warn_on_tabs {
+            violations.extend(tab_lines(&blocks));
+        }
+        for violation in &violations {
+            writeln!(Output, "{violation}")?;
+        }
+        return Ok(if violations.is_empty() { 0 } else { 1 });
+    }
+
+    if let Some(max) = args.max_line_length {
+        let violations = long_lines(&blocks, max, args.ignore_urls);
+        for violation in &violations {
+            writeln!(Output, "{violation}")?;
+        }
+        return Ok(if violations.is_empty() { 0 } else { 1 });
+    }
+
+    if let LangSelector::List = lang_selector {
+        if args.wants_json() {
+            let inventory = language_inventory(&blocks);
+            write_json(&inventory, args.json_compact)?;
+            if inventory.is_empty() && args.fail_on_empty {
+                return Ok(1);
+            }
+            return Ok(0);
+        }
+        let langs = languages(&blocks);
+        if langs.is_empty() {
+            eprintln!("no languages found");
+            if args.fail_on_empty {
+                return Ok(1);
+            }
+        }
+        for lang in langs {
+            writeln!(Output, "{lang}")?;
+        }
+        return Ok(0);
+    }
+
+    if args.summary_json || (args.stats && args.wants_json()) {
+        let summary = Summary::new(&blocks, &source_names, &config.aliases);
+        write_json(&summary, args.json_compact)?;
+        return Ok(0);
+    }
+
+    if blocks.is_empty() {
+        if args.allow_empty {
+            return Ok(0);
+        }
+        eprintln!("No matching code blocks found.");
+        return Ok(1);
+    }
+
+    if let Some(key) = args.sort_within_source {
+        sort_within_source(&mut blocks, key);
+    }
+
+    if args.strip_comments {
+        for block in &mut blocks {
+            if let Some(syntax) = comment_syntax(block.lang.as_deref()) {
+                block.code = strip_comments(&block.code, syntax);
+            }
+        }
+    }
+
+    if args.strip_leading_blank {
+        for block in &mut blocks {
+            let source = raw_sources.get(&block.source).map(String::as_str);
+            strip_leading_blank(block, source);
+        }
+    }
+
+    if args.dedent {
+        for block in &mut blocks {
+            block.code = dedent(&block.code);
+        }
+    }
+
+    if args.transform.is_some() || !args.transform_lang.is_empty() {
+        let mut by_lang = Vec::new();
+        for mapping in &args.transform_lang {
+            let (lang, cmd) = mapping.split_once('=').ok_or_else(|| {
+                format!("invalid --transform-lang '{mapping}', expected LANG=CMD")
+            })?;
+            by_lang.push((lang.to_string(), cmd.to_string()));
+        }
+        for block in &mut blocks {
+            let cmd = by_lang
+                .iter()
+                .find(|(lang, _)| matches_lang(block, lang, &config.aliases))
+                .map(|(_, cmd)| cmd.as_str())
+                .or(args.transform.as_deref());
+            let Some(cmd) = cmd else {
+                continue;
+            };
+            match run_transform(cmd, &block.code) {
+                Ok(code) => block.code = code,
+                Err(e) if args.transform_strict => {
+                    return Err(format!("{}: transform failed: {e}", block_label(block)).into());
+                }
+                Err(e) => warn(
+                    Some(&block.source),
+                    block.start_line,
+                    format!(
+                        "{}: transform failed, keeping original: {e}",
+                        block_label(block)
+                    ),
+                ),
+            }
+        }
+    }
+
+    if args.split_prompts {
+        for block in blocks
+            .iter_mut()
+            .filter(|b| is_session_lang(b.lang.as_deref()))
+        {
+            let commands = session_commands(&block.code);
+            if args.commands_only {
+                block.code = commands.join("\n");
+            }
+            block.commands = Some(commands);
+        }
+    }
+
+    if let Some(chars) = &args.strip_prompt {
+        for block in blocks
+            .iter_mut()
+            .filter(|b| is_shell_lang(b.lang.as_deref()))
+        {
+            let chars = prompt_chars(block.lang.as_deref(), chars);
+            block.code = strip_prompts(&block.code, &chars);
+        }
+    }
+    if args.doctest_strip {
+        for block in &mut blocks {
+            if let Some(code) = strip_doctest(block.lang.as_deref(), &block.code) {
+                block.code = code;
+            }
+        }
+    }
+
+    if let Some(path) = &args.tar {
+        let template = args
+            .output_template
+            .as_deref()
+            .unwrap_or(DEFAULT_TAR_TEMPLATE);
+        let outputs = plan_outputs(&blocks, template, args.force)?;
+        if args.dry_run {
+            // List the entries the archive would hold, wherever it would go.
+            write_outputs(&outputs, true)?;
+            return Ok(0);
+        }
+        let out: Box<dyn Write> = if path.as_os_str() == "-" {
+            Box::new(Output)
+        } else {
+            Box::new(fs::File::create(path)?)
+        };
+        let mtime = if args.reproducible {
+            0
+        } else {
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs())
+        };
+        if args.tar_gz {
+            let gz = flate2::write::GzEncoder::new(out, flate2::Compression::default());
+            write_tar(gz, &outputs, mtime)?.finish()?.flush()?;
+        } else {
+            write_tar(out, &outputs, mtime)?.flush()?;
+        }
+        return Ok(0);
+    }
+
+    if let Some(template) = &args.output_template {
+        let outputs = plan_outputs(&blocks, template, args.force)?;
+        write_outputs(&outputs, args.dry_run)?;
+        if let Some(map_path) = &args.source_map {
+            let entries = outputs
+                .iter()
+                .map(|(path, b)| SourceMapEntry::new(b, Some(path)))
+                .collect();
+            write_source_map(map_path, entries, args.dry_run)?;
+        }
+        return Ok(0);
+    }
+
+    if let Some(map_path) = &args.source_map {
+        let entries = blocks
+            .iter()
+            .map(|b| SourceMapEntry::new(b, None))
+            .collect();
+        write_source_map(map_path, entries, args.dry_run)?;
+    }
+
+    if let Some(path) = &args.merge_into {
+        if !args.allow_mixed {
+            let langs: BTreeSet<String> = collate(&blocks, &config.aliases).into_keys().collect();
+            if langs.len() > 1 {
+                let langs: Vec<String> = langs.into_iter().collect();
+                return Err(format!(
+                    "selected blocks mix languages ({}); pass --allow-mixed to merge anyway",
+                    langs.join(", ")
+                )
+                .into());
+            }
+        }
+        let merged = merge_blocks(&blocks, args.prepend.as_deref(), args.append.as_deref());
+        if args.dry_run {
+            report_write(path, merged.len())?;
+        } else {
+            fs::write(path, merged)?;
+        }
+        return Ok(0);
+    }
+
+    let json_fields = JsonFields {
+        line_numbers: args.line_numbers,
+        headings: args.context_headings,
+        annotate: args.annotate.then_some(&config.aliases),
+        block_ids: args.block_ids,
+    };
+
+    if let Some(path) = &args.output_json_lines_to {
+        if args.dry_run {
+            let mut preview = Vec::new();
+            write_json_lines(&mut preview, &blocks, json_fields)?;
+            report_write(path, preview.len())?;
+        } else {
+            let file = io::BufWriter::new(fs::File::create(path)?);
+            write_json_lines(file, &blocks, json_fields)?;
+        }
+        return Ok(0);
+    }
+
+    let render = RenderOptions {
+        fenced: args.fenced,
+        fence_char: args.fence_char,
+        show_fences: args.show_fences,
+        preserve_trailing: args.preserve_trailing,
+        source_comment: args.source_comment,
+        line_numbers: args.line_numbers,
+        number_start: args.number_start,
+        gutter_width: args.gutter_width,
+        number_lines: args
+            .number_lines
+            .as_deref()
+            .map(parse_line_selection)
+            .transpose()?,
+        preview_width: args.preview_width(),
+        sections: args.split_on_heading.is_some(),
+        skip_empty: args.skip_empty_output,
+        indent: args.indent.unwrap_or(0),
+    };
+
+    if args.imports {
+        if args.wants_json() {
+            let payload: Vec<BlockImports> = blocks
+                .iter()
+                .map(|b| BlockImports {
+                    index: b.index,
+                    source: &b.source,
+                    lang: b.lang.as_deref(),
+                    imports: block_imports(b, &config.aliases),
+                })
+                .collect();
+            write_json(&payload, args.json_compact)?;
+        } else {
+            let all: BTreeSet<String> = blocks
+                .iter()
+                .flat_map(|b| block_imports(b, &config.aliases))
+                .collect();
+            for import in all {
+                writeln!(Output, "{import}")?;
+            }
+        }
+        return Ok(0);
+    }
+
+    if args.collate {
+        let groups = collate(&blocks, &config.aliases);
+        if args.wants_json() {
+            let payload: BTreeMap<&str, Vec<JsonBlock>> = groups
+                .iter()
+                .map(|(lang, group)| {
+                    let json = group
+                        .iter()
+                        .map(|b| JsonBlock::new(b, json_fields))
+                        .collect();
+                    (lang.as_str(), json)
+                })
+                .collect();
+            write_json(&payload, args.json_compact)?;
+        } else {
+            writeln!(
+                Output,
+                "{}",
+                render_collated(&groups, &render, &args.separator)
+            )?;
+        }
+        return Ok(0);
+    }
+
+    if args.json_envelope {
+        let envelope = Envelope::new(&blocks, json_fields, !args.reproducible);
+        write_json(&envelope, args.json_compact)?;
+        return Ok(0);
+    }
+
+    if args.ndjson_warnings {
+        let mut out = Output;
+        write_tagged_ndjson(
+            &mut out,
+            &blocks,
+            json_fields,
+            &source_names,
+            take_warnings(),
+        )?;
+        out.flush()?;
+        return Ok(0);
+    }
+
+    if args.wants_json() {
+        let style = args.json_style.unwrap_or_default();
+        emit_json(Output, &blocks, json_fields, style, args.json_compact)?;
+        return Ok(0);
+    }
+
+    if args.list {
+        let width = args.preview_width();
+        for block in &blocks {
+            let line = match &args.list_format {
+                Some(template) => format_list_line(template, block),
+                None => list_line(block, args.line_numbers, args.context_headings),
+            };
+            writeln!(Output, "{}", fit_width(&line, width))?;
+        }
+        return Ok(0);
+    }
+
+    if args.stats {
+        write!(Output, "{}", render_stats(&blocks, &config.aliases))?;
+        return Ok(0);
+    }
+
+    if args.to_markdown {
+        write!(Output, "{}", render_markdown(&blocks, &render))?;
+        return Ok(0);
+    }
+
+    if let Some(n) = args.peek {
+        let peeks: Vec<String> = blocks
+            .iter()
+            .map(|b| render_peek(b, n, args.line_numbers, args.context_headings))
+            .collect();
+        let width = args.preview_width();
+        let output = peeks.join("\n\n");
+        let lines: Vec<String> = output.lines().map(|l| fit_width(l, width)).collect();
+        writeln!(Output, "{}", lines.join("\n"))?;
+        return Ok(0);
+    }
+
+    if args.print_raw_bytes {
+        let mut out = Output;
+        for block in &blocks {
+            out.write_all(source_span(block, &raw_sources)?.as_bytes())?;
+        }
+        out.flush()?;
+        return Ok(0);
+    }
+
+    if let Some(target) = args.escape_for {
+        for block in &mut blocks {
+            block.code = escape_code(&block.code, target);
+        }
+    }
+
+    if let Some(pattern) = &grep
+        && args.highlight_matches
+        && args.color.enabled()
+    {
+        for block in &mut blocks {
+            block.code = highlight_matches(&block.code, pattern);
+        }
+    }
+
+    let final_newline = if args.trailing_newline {
+        Some(true)
+    } else if args.no_trailing_newline {
+        Some(false)
+    } else {
+        None
+    };
+    write!(
+        Output,
+        "{}",
+        raw_output(
+            &blocks,
+            &render,
+            &args.separator,
+            args.file_separator.as_deref(),
+            final_newline,
+        )
+    )?;
+    Ok(0)
 }
 
-#[derive(Debug)]
-struct FenceState {
-    fence_char: char,
-    fence_len: usize,
-    lang: Option<String>,
-    buffer: String,
-    start_line: usize,
+/// The source text a block was parsed from, byte for byte.
+fn source_span<'a>(
+    block: &CodeBlock,
+    sources: &'a BTreeMap<String, String>,
+) -> Result<&'a str, Box<dyn std::error::Error>> {
+    let content = sources.get(&block.source);
+    match (content, block.byte_start, block.byte_end) {
+        (Some(content), Some(start), Some(end)) => content.get(start..end).ok_or_else(|| {
+            format!(
+                "{}: byte span {start}..{end} is out of range",
+                block_label(block)
+            )
+            .into()
+        }),
+        _ => Err(format!(
+            "{}: no byte span in the source (notebook cells have none)",
+            block_label(block)
+        )
+        .into()),
+    }
 }
 
-fn parse_fence_start(line: &str) -> Option<(char, usize, Option<String>)> {
-    let trimmed = line.trim_start();
-    let (fence_char, fence_len) = if trimmed.starts_with("```") {
-        ('`', trimmed.chars().take_while(|c| *c == '`').count())
-    } else if trimmed.starts_with("~~~") {
-        ('~', trimmed.chars().take_while(|c| *c == '~').count())
-    } else {
-        return None;
+/// Copies up to `before` and `after` source lines around each block into its
+/// context, stopping at the ends of the file and at any fenced block's lines.
+fn attach_context(
+    blocks: &mut [CodeBlock],
+    sources: &BTreeMap<String, String>,
+    before: usize,
+    after: usize,
+) {
+    let span = |b: &CodeBlock| {
+        let first = b.fence_start_line.or(b.start_line)?;
+        Some((first, b.fence_end_line.or(b.end_line).unwrap_or(first)))
     };
+    let mut fences: BTreeMap<String, Vec<(usize, usize)>> = BTreeMap::new();
+    for block in blocks.iter().filter(|b| b.kind == BlockKind::Fenced) {
+        if let Some(span) = span(block) {
+            fences.entry(block.source.clone()).or_default().push(span);
+        }
+    }
 
-    let lang = trimmed
-        .chars()
-        .skip(fence_len)
-        .collect::<String>()
-        .trim()
-        .to_string();
-    let lang = if lang.is_empty() { None } else { Some(lang) };
+    for block in blocks.iter_mut() {
+        let (Some((first, last)), Some(content)) = (span(block), sources.get(&block.source)) else {
+            continue;
+        };
+        let lines: Vec<&str> = content.lines().collect();
+        let spans = fences
+            .get(&block.source)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        let is_prose = |line: &usize| {
+            (1..=lines.len()).contains(line) && !spans.iter().any(|(a, b)| (a..=b).contains(&line))
+        };
+        let prose = |n: usize| lines[n - 1].to_string();
+        block.context_before = (first.saturating_sub(before)..first)
+            .rev()
+            .take_while(is_prose)
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .map(prose)
+            .collect();
+        block.context_after = (last + 1..=last + after)
+            .take_while(is_prose)
+            .map(prose)
+            .collect();
+    }
+}
 
-    Some((fence_char, fence_len, lang))
+fn parse_lang_selector(arg: &Option<Option<String>>) -> LangSelector {
+    match arg {
+        None => LangSelector::All,
+        Some(None) => LangSelector::List,
+        Some(Some(lang)) => LangSelector::Filter(vec![lang.to_lowercase()]),
+    }
 }
 
-fn is_closing_fence(line: &str, fence_char: char, fence_len: usize) -> bool {
-    let trimmed = line.trim_start();
-    let prefix_len = trimmed.chars().take_while(|c| *c == fence_char).count();
-    prefix_len >= fence_len && prefix_len >= 3
+fn read_lang_file(path: &Path) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("cannot read language file {}: {e}", path.display()))?;
+    Ok(parse_lang_list(&text))
 }
 
-fn parse_inline_blocks(line: &str, line_no: usize, source: &str) -> Vec<CodeBlock> {
-    let mut blocks = Vec::new();
-    let mut start_tick: Option<usize> = None;
-    let mut start_idx: Option<usize> = None;
-    let bytes = line.as_bytes();
-    let mut i = 0;
+/// Parses a newline-separated language list, skipping blanks and `#` comments.
+fn parse_lang_list(text: &str) -> Vec<String> {
+    text.lines()
+        .map(|l| l.split('#').next().unwrap_or_default().trim())
+        .filter(|l| !l.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+fn resolve_encoding(label: &str) -> Result<&'static Encoding, Box<dyn std::error::Error>> {
+    Encoding::for_label(label.as_bytes())
+        .ok_or_else(|| format!("unknown encoding '{label}'").into())
+}
+
+/// How input bytes are turned into text.
+#[derive(Debug, Clone, Copy)]
+enum Charset {
+    Fixed(&'static Encoding),
+    /// Sniff each input; `verbose` logs the guess to stderr.
+    Detect {
+        verbose: bool,
+    },
+}
+
+impl Charset {
+    fn from_args(args: &Args) -> Result<Self, Box<dyn std::error::Error>> {
+        if args.encoding_detect {
+            Ok(Charset::Detect {
+                verbose: args.verbose,
+            })
+        } else {
+            Ok(Charset::Fixed(resolve_encoding(&args.encoding)?))
+        }
+    }
+
+    fn encoding_for(self, bytes: &[u8], name: &str) -> &'static Encoding {
+        match self {
+            Charset::Fixed(encoding) => encoding,
+            Charset::Detect { verbose } => {
+                let encoding = detect_encoding(bytes);
+                if verbose {
+                    eprintln!("{name}: detected {}", encoding.name());
+                }
+                encoding
+            }
+        }
+    }
+}
+
+/// Guesses the charset of `bytes`; a BOM or valid UTF-8 always wins.
+fn detect_encoding(bytes: &[u8]) -> &'static Encoding {
+    if let Some((encoding, _)) = Encoding::for_bom(bytes) {
+        return encoding;
+    }
+    if std::str::from_utf8(bytes).is_ok() {
+        return encoding_rs::UTF_8;
+    }
+    let mut detector = EncodingDetector::new(Iso2022JpDetection::Deny);
+    detector.feed(bytes, true);
+    detector.guess(None, Utf8Detection::Allow)
+}
+
+fn read_file(path: &Path, charset: Charset) -> Result<InputSource, Box<dyn std::error::Error>> {
+    let name = path.display().to_string();
+    let bytes = fs::read(path)?;
+    let encoding = charset.encoding_for(&bytes, &name);
+    Ok(InputSource {
+        content: decode_input(&bytes, &name, encoding)?,
+        name,
+    })
+}
+
+fn has_glob_meta(s: &str) -> bool {
+    s.contains(['*', '?', '[', '{'])
+}
+
+/// Expands input arguments that are globs rather than existing paths, each
+/// into its matches in sorted order. With `recursive`, directories expand to
+/// the documents below them. URLs and existing files pass through.
+fn expand_inputs(
+    files: &[PathBuf],
+    case_insensitive: bool,
+    mut recursive: Option<&mut Walk>,
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let mut expanded = Vec::new();
+    for path in files {
+        if let Some(walk) = recursive.as_deref_mut()
+            && path.is_dir()
+        {
+            let mut found = Vec::new();
+            walk.files(path, &mut found)?;
+            let mut documents: Vec<PathBuf> = found
+                .into_iter()
+                .filter(|p| {
+                    p.extension().is_some() && InputFormat::detect(&p.to_string_lossy()).is_some()
+                })
+                .collect();
+            documents.sort();
+            expanded.append(&mut documents);
+            continue;
+        }
+        let Some(pattern) = path
+            .to_str()
+            .filter(|p| has_glob_meta(p) && remote_url(path).is_none() && !path.exists())
+        else {
+            expanded.push(path.clone());
+            continue;
+        };
+        let matcher = input_glob(pattern, case_insensitive)?;
+        // Walk only below the pattern's literal leading directories.
+        let literal: Vec<&str> = pattern
+            .split('/')
+            .take_while(|part| !has_glob_meta(part))
+            .collect();
+        let base = match literal.join("/") {
+            root if root.is_empty() && pattern.starts_with('/') => PathBuf::from("/"),
+            dir if dir.is_empty() => PathBuf::from("."),
+            dir => PathBuf::from(dir),
+        };
+        let base = if case_insensitive {
+            resolve_ignoring_case(&base).unwrap_or(base)
+        } else {
+            base
+        };
+        let mut matches = Vec::new();
+        Walk::default().files(&base, &mut matches)?;
+        let mut matches: Vec<PathBuf> = matches
+            .into_iter()
+            .map(|p| p.strip_prefix("./").map(Path::to_path_buf).unwrap_or(p))
+            .filter(|p| matcher.is_match(p))
+            .collect();
+        if matches.is_empty() {
+            return Err(format!("no files match {pattern}").into());
+        }
+        matches.sort();
+        expanded.append(&mut matches);
+    }
+    Ok(expanded)
+}
+
+/// Input globs: `*`, `?` and `[...]` stay within one path component, `**`
+/// crosses directories and `{a,b}` matches either alternative.
+fn input_glob(
+    pattern: &str,
+    case_insensitive: bool,
+) -> Result<GlobMatcher, Box<dyn std::error::Error>> {
+    let glob = GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .case_insensitive(case_insensitive)
+        .build()?;
+    Ok(glob.compile_matcher())
+}
+
+/// Finds the existing directory spelled like `path` up to letter case.
+fn resolve_ignoring_case(path: &Path) -> Option<PathBuf> {
+    let mut resolved = PathBuf::new();
+    for component in path.components() {
+        let exact = resolved.join(component);
+        if exact.exists() {
+            resolved = exact;
+            continue;
+        }
+        let wanted = component.as_os_str().to_str()?.to_lowercase();
+        let dir = if resolved.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            &resolved
+        };
+        let entry = fs::read_dir(dir).ok()?.flatten().find(|e| {
+            e.file_name()
+                .to_str()
+                .is_some_and(|n| n.to_lowercase() == wanted)
+        })?;
+        resolved.push(entry.file_name());
+    }
+    Some(resolved)
+}
+
+/// A directory walk; symlinked directories are skipped unless followed.
+#[derive(Debug, Default)]
+struct Walk {
+    follow_symlinks: bool,
+    /// Log skipped symlinks and cycles to stderr.
+    verbose: bool,
+    /// Canonical directories already entered, so symlink cycles end.
+    visited: BTreeSet<PathBuf>,
+}
+
+impl Walk {
+    /// Collects the files below `dir`.
+    fn files(&mut self, dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+        if !dir.is_dir() {
+            return Ok(());
+        }
+        if !self.visited.insert(dir.canonicalize()?) {
+            if self.verbose {
+                eprintln!("{}: skipped, directory already visited", dir.display());
+            }
+            return Ok(());
+        }
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                self.files(&path, files)?;
+            } else if file_type.is_symlink() && path.is_dir() {
+                if self.follow_symlinks {
+                    self.files(&path, files)?;
+                } else if self.verbose {
+                    eprintln!("{}: skipped symlinked directory", path.display());
+                }
+            } else {
+                files.push(path);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An `http://` or `https://` input argument.
+fn remote_url(path: &Path) -> Option<&str> {
+    path.to_str()
+        .filter(|s| s.starts_with("http://") || s.starts_with("https://"))
+}
+
+/// Give up on a remote document after this long.
+#[cfg(feature = "reqwest")]
+const REMOTE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+#[cfg(feature = "reqwest")]
+fn fetch_url(url: &str, charset: Charset) -> Result<InputSource, Box<dyn std::error::Error>> {
+    let client = reqwest::blocking::Client::builder()
+        .timeout(REMOTE_TIMEOUT)
+        .build()?;
+    let response = client.get(url).send().map_err(|e| {
+        if e.is_timeout() {
+            format!(
+                "timed out after {}s fetching {url}",
+                REMOTE_TIMEOUT.as_secs()
+            )
+        } else {
+            // reqwest's own message omits the cause (DNS, TLS, refused...).
+            let mut message = format!("failed to fetch {url}: {e}");
+            let mut cause = std::error::Error::source(&e);
+            while let Some(err) = cause {
+                message.push_str(&format!(": {err}"));
+                cause = err.source();
+            }
+            message
+        }
+    })?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(format!("failed to fetch {url}: HTTP {status}").into());
+    }
+    let bytes = response
+        .bytes()
+        .map_err(|e| format!("failed to read {url}: {e}"))?;
+    Ok(InputSource {
+        content: decode_input(&bytes, url, charset.encoding_for(&bytes, url))?,
+        name: url.to_string(),
+    })
+}
+
+#[cfg(not(feature = "reqwest"))]
+fn fetch_url(url: &str, _charset: Charset) -> Result<InputSource, Box<dyn std::error::Error>> {
+    Err(format!("cannot fetch {url}: mdcode was built without the `reqwest` feature").into())
+}
+
+/// Reads every input, piped stdin first. The flag reports whether
+/// `--keep-going` skipped any of them.
+fn collect_inputs(
+    args: &Args,
+    piped_stdin: Option<&mut dyn Read>,
+) -> Result<(Vec<InputSource>, bool), Box<dyn std::error::Error>> {
+    let charset = Charset::from_args(args)?;
+    let mut sources = Vec::new();
+    let mut skipped = false;
+    let mut terminal = io::stdin();
+    let stdin: Option<&mut dyn Read> = match piped_stdin {
+        Some(piped) => Some(piped),
+        None if args.files.is_empty() => Some(&mut terminal),
+        None => None,
+    };
+
+    if let Some(stdin) = stdin {
+        let mut bytes = Vec::new();
+        stdin.read_to_end(&mut bytes)?;
+        if !bytes.is_empty() || args.files.is_empty() {
+            sources.push(InputSource {
+                name: "stdin".to_string(),
+                content: decode_input(&bytes, "stdin", charset.encoding_for(&bytes, "stdin"))?,
+            });
+        }
+    }
+
+    let mut walk = Walk {
+        follow_symlinks: args.follow_symlinks,
+        verbose: args.verbose,
+        ..Default::default()
+    };
+    let files = expand_inputs(
+        &args.files,
+        args.glob_case_insensitive,
+        args.recursive.then_some(&mut walk),
+    )?;
+    let progress = shows_progress(args.progress, io::stderr().is_terminal(), files.len())
+        .then(|| progress_bar(files.len()));
+
+    for path in &files {
+        let source = match remote_url(path) {
+            Some(url) if !args.allow_remote => {
+                return Err(format!("{url} is a URL; pass --allow-remote to fetch it").into());
+            }
+            Some(url) => fetch_url(url, charset),
+            None => read_file(path, charset),
+        };
+        match source {
+            Ok(source) => sources.push(source),
+            Err(e) if args.keep_going => {
+                eprintln!("error: {}: {e}", path.display());
+                skipped = true;
+            }
+            Err(e) => return Err(e),
+        }
+        if let Some(bar) = &progress {
+            bar.inc(1);
+        }
+    }
+
+    if let Some(bar) = progress {
+        bar.finish_and_clear();
+    }
+
+    if args.reverse_files {
+        sources.reverse();
+    }
+
+    Ok((sources, skipped))
+}
+
+/// A source name with `/` separators, so `--reproducible` output matches
+/// across platforms.
+fn portable_name(name: &str) -> String {
+    name.replace('\\', "/")
+}
+
+/// Below this many files reading is quick enough that a bar is just flicker.
+const PROGRESS_MIN_INPUTS: usize = 50;
+
+/// Whether `--progress` draws a bar: only on a terminal, for enough inputs.
+fn shows_progress(requested: bool, terminal: bool, inputs: usize) -> bool {
+    requested && terminal && inputs >= PROGRESS_MIN_INPUTS
+}
+
+fn progress_bar(len: usize) -> ProgressBar {
+    let bar = ProgressBar::new(len as u64);
+    if let Ok(style) = ProgressStyle::with_template("{bar:40} {pos}/{len} files") {
+        bar.set_style(style);
+    }
+    bar
+}
+
+fn decode_input(
+    bytes: &[u8],
+    name: &str,
+    encoding: &'static Encoding,
+) -> Result<String, Box<dyn std::error::Error>> {
+    if encoding == encoding_rs::UTF_8 {
+        return String::from_utf8(bytes.to_vec()).map_err(|_| {
+            format!("{name} is not valid UTF-8; pass --encoding <LABEL> to decode it").into()
+        });
+    }
+
+    let (content, _, had_errors) = encoding.decode(bytes);
+    if had_errors {
+        warn(
+            Some(name),
+            None,
+            format!(
+                "{name} contains bytes invalid in {}; replaced with U+FFFD",
+                encoding.name()
+            ),
+        );
+    }
+    Ok(content.into_owned())
+}
+
+#[derive(Debug, Default)]
+struct ParseOptions {
+    include_inline: bool,
+    /// Explain inline span detection on stderr.
+    inline_debug: bool,
+    /// Parser forced for every input; detected per input when unset.
+    format: Option<InputFormat>,
+    as_code: bool,
+    blockquotes: bool,
+    /// Extract inline code from table cells with their coordinates.
+    tables: bool,
+    /// `--between` delimiters, replacing format detection for every input.
+    between: Option<(String, String)>,
+    /// Directory of memoized parses; `None` parses every input afresh.
+    cache_dir: Option<PathBuf>,
+}
+
+fn collect_blocks(inputs: Vec<InputSource>, parse: &ParseOptions) -> Vec<CodeBlock> {
+    let mut blocks = Vec::new();
+    for input in inputs {
+        // --inline-debug reports are printed while parsing, so a cache hit would lose them.
+        let mut parsed = match &parse.cache_dir {
+            Some(dir) if !parse.inline_debug => cached_parse(dir, &input, parse),
+            _ => parse_input(&input, parse),
+        };
+        blocks.append(&mut parsed);
+    }
+
+    // Blocks sharing a source and first line, counted so far.
+    let mut occurrences: BTreeMap<(String, String), usize> = BTreeMap::new();
+    for (index, block) in blocks.iter_mut().enumerate() {
+        block.index = index;
+        block.hash = content_hash(&block.code);
+        let first_line = block.code.lines().next().unwrap_or_default().to_string();
+        let seen = occurrences
+            .entry((block.source.clone(), first_line))
+            .or_default();
+        block.block_id = block_id(block, *seen);
+        *seen += 1;
+    }
+
+    blocks
+}
+
+fn parse_input(input: &InputSource, parse: &ParseOptions) -> Vec<CodeBlock> {
+    if let Some((open, close)) = &parse.between {
+        return parse_between_blocks(input, open, close);
+    }
+    match parse.format.or_else(|| InputFormat::detect(&input.name)) {
+        None if parse.as_code => vec![whole_file_block(input)],
+        None | Some(InputFormat::Markdown) => parse_blocks(input, parse),
+        Some(InputFormat::Rst) => parse_rst_blocks(input),
+        Some(InputFormat::Adoc) => parse_adoc_blocks(input),
+        Some(InputFormat::Ipynb) => parse_ipynb_blocks(input),
+        Some(InputFormat::Rustdoc) => parse_rustdoc_blocks(input, parse),
+    }
+}
+
+/// A stored parse: the blocks and the warnings parsing raised, replayed on
+/// every hit so a cached run reports what a fresh one would.
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+    blocks: Vec<CodeBlock>,
+    warnings: Vec<Warning>,
+}
+
+/// Reuses the stored parse of an unchanged input, or parses and stores it.
+/// The cache is best effort: unreadable or stale entries are reparsed and
+/// failed writes are ignored.
+fn cached_parse(dir: &Path, input: &InputSource, parse: &ParseOptions) -> Vec<CodeBlock> {
+    let path = dir.join(format!("{}.json", cache_key(input, parse)));
+    let entry = match fs::read(&path)
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+    {
+        Some(entry) => entry,
+        None => {
+            let (blocks, warnings) = capture_warnings(|| parse_input(input, parse));
+            let entry = CacheEntry { blocks, warnings };
+            if let Ok(json) = serde_json::to_vec(&entry) {
+                let _ = fs::create_dir_all(dir).and_then(|()| fs::write(&path, json));
+            }
+            entry
+        }
+    };
+    for warning in entry.warnings {
+        warn(warning.source.as_deref(), warning.line, warning.message);
+    }
+    entry.blocks
+}
+
+/// Hashes everything a parse depends on: the mdcode version, the source
+/// name, its content and the parser options.
+fn cache_key(input: &InputSource, parse: &ParseOptions) -> String {
+    let options = format!(
+        "{:?}",
+        (
+            parse.include_inline,
+            parse.format,
+            parse.as_code,
+            parse.blockquotes,
+            parse.tables,
+            &parse.between
+        )
+    );
+    content_hash(
+        &[
+            env!("CARGO_PKG_VERSION"),
+            &input.name,
+            &content_hash(&input.content),
+            &options,
+        ]
+        .join("\0"),
+    )
+}
+
+/// The `--cache` location: `$XDG_CACHE_HOME/mdcode`, else `~/.cache/mdcode`.
+fn default_cache_dir() -> PathBuf {
+    env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+        .unwrap_or_else(|| PathBuf::from(".cache"))
+        .join("mdcode")
+}
+
+/// Merges blocks that directly follow each other in a source and share a
+/// language, separating their bodies with a blank line. The merged block
+/// keeps the first block's index and spans through the last one. Inline
+/// spans, listed in `inline_spans`, neither join nor separate a run.
+fn join_adjacent(blocks: Vec<CodeBlock>, inline_spans: &BTreeSet<usize>) -> Vec<CodeBlock> {
+    let mut joined: Vec<CodeBlock> = Vec::new();
+    // Position in `joined` of the run being extended.
+    let mut run = None;
+    // Index of the last block folded into that run.
+    let mut last_index = 0;
+    for block in blocks {
+        if block.kind == BlockKind::Inline {
+            joined.push(block);
+            continue;
+        }
+        if let Some(prev) = run.map(|at| &mut joined[at])
+            && block.index > last_index
+            && (last_index + 1..block.index).all(|i| inline_spans.contains(&i))
+            && continues_run(prev, &block)
+        {
+            prev.code = format!("{}\n\n{}", prev.code, block.code);
+            prev.hash = content_hash(&prev.code);
+            prev.end_line = block.end_line;
+            prev.byte_end = block.byte_end;
+            prev.trailing_newlines = block.trailing_newlines;
+            prev.fence_end_line = block.fence_end_line;
+            prev.fence_close = block.fence_close;
+            prev.closed = block.closed;
+            last_index = block.index;
+            continue;
+        }
+        last_index = block.index;
+        run = Some(joined.len());
+        joined.push(block);
+    }
+    joined
+}
+
+fn continues_run(prev: &CodeBlock, next: &CodeBlock) -> bool {
+    prev.kind == BlockKind::Fenced
+        && next.kind == BlockKind::Fenced
+        && prev.source == next.source
+        && matches!((&prev.lang, &next.lang), (Some(a), Some(b)) if a.eq_ignore_ascii_case(b))
+}
+
+fn content_hash(code: &str) -> String {
+    Sha256::digest(code.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Hex digits kept from the `--block-ids` digest.
+const BLOCK_ID_LEN: usize = 16;
+
+/// Hashes the source name, the first line of code and how many earlier
+/// blocks of the source start with that line, so the ID ignores the block's
+/// position and the rest of its body yet stays unique within the source.
+fn block_id(block: &CodeBlock, occurrence: usize) -> String {
+    let first_line = block.code.lines().next().unwrap_or_default();
+    let mut id = content_hash(&format!("{}\0{first_line}\0{occurrence}", block.source));
+    id.truncate(BLOCK_ID_LEN);
+    id
+}
+
+/// Keeps blocks whose hash starts with `prefix`. Several blocks may match
+/// when they share content; distinct hashes sharing the prefix are an error.
+fn select_by_hash(
+    blocks: Vec<CodeBlock>,
+    prefix: &str,
+) -> Result<Vec<CodeBlock>, Box<dyn std::error::Error>> {
+    if prefix.is_empty() || !prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("invalid hash prefix '{prefix}'").into());
+    }
+    let prefix = prefix.to_ascii_lowercase();
+    let selected: Vec<CodeBlock> = blocks
+        .into_iter()
+        .filter(|b| b.hash.starts_with(&prefix))
+        .collect();
+    let distinct: BTreeSet<&str> = selected.iter().map(|b| b.hash.as_str()).collect();
+    if distinct.len() > 1 {
+        let indices: Vec<String> = selected.iter().map(|b| b.index.to_string()).collect();
+        return Err(format!(
+            "hash prefix '{prefix}' is ambiguous (blocks {})",
+            indices.join(", ")
+        )
+        .into());
+    }
+    Ok(selected)
+}
+
+const MARKDOWN_EXTENSIONS: &[&str] = &["md", "markdown", "mdown", "mkd", "mdx"];
+
+impl InputFormat {
+    /// The document format implied by a file name, or `None` for other
+    /// files. Stdin and extensionless names are assumed to be Markdown.
+    fn detect(name: &str) -> Option<Self> {
+        if name == "stdin" {
+            return Some(InputFormat::Markdown);
+        }
+        let Some(ext) = Path::new(name).extension() else {
+            return Some(InputFormat::Markdown);
+        };
+        let ext = ext.to_string_lossy().to_lowercase();
+        match ext.as_str() {
+            _ if MARKDOWN_EXTENSIONS.contains(&ext.as_str()) => Some(InputFormat::Markdown),
+            "rst" | "rest" => Some(InputFormat::Rst),
+            "adoc" | "asciidoc" | "asc" => Some(InputFormat::Adoc),
+            "ipynb" => Some(InputFormat::Ipynb),
+            _ => None,
+        }
+    }
+}
+
+/// Byte offset where each line starts, plus the total length at the end.
+fn line_offsets(content: &str) -> Vec<usize> {
+    let mut offsets = vec![0];
+    for line in content.split_inclusive('\n') {
+        offsets.push(offsets[offsets.len() - 1] + line.len());
+    }
+    offsets
+}
+
+fn leading_width(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
+
+/// Extracts `.. code-block:: LANG` (also `code` and `sourcecode`) directives
+/// and `::` literal blocks: the indented lines after the marker line.
+fn parse_rst_blocks(input: &InputSource) -> Vec<CodeBlock> {
+    let lines: Vec<&str> = input.content.lines().collect();
+    let offsets = line_offsets(&input.content);
+    let mut blocks = Vec::new();
+    let mut i = 0;
+
+    while i < lines.len() {
+        let line = lines[i];
+        let trimmed = line.trim();
+        let base = leading_width(line);
+        let directive = ["code-block::", "code::", "sourcecode::"]
+            .iter()
+            .find_map(|d| trimmed.strip_prefix("..")?.trim_start().strip_prefix(d));
+        let literal = directive.is_none() && !trimmed.starts_with("..") && trimmed.ends_with("::");
+        if directive.is_none() && !literal {
+            i += 1;
+            continue;
+        }
+
+        let mut j = i + 1;
+        // Directive options such as `:linenos:` precede the body.
+        while directive.is_some()
+            && j < lines.len()
+            && leading_width(lines[j]) > base
+            && lines[j].trim_start().starts_with(':')
+        {
+            j += 1;
+        }
+        while j < lines.len() && lines[j].trim().is_empty() {
+            j += 1;
+        }
+        let start = j;
+        while j < lines.len() && (lines[j].trim().is_empty() || leading_width(lines[j]) > base) {
+            j += 1;
+        }
+        let mut end = j;
+        while end > start && lines[end - 1].trim().is_empty() {
+            end -= 1;
+        }
+        if end == start {
+            i += 1;
+            continue;
+        }
+
+        let body = &lines[start..end];
+        let margin = body
+            .iter()
+            .filter(|l| !l.trim().is_empty())
+            .map(|l| leading_width(l))
+            .min()
+            .unwrap_or(0);
+        let code: Vec<&str> = body.iter().map(|l| l.get(margin..).unwrap_or("")).collect();
+        let lang = directive.map(str::trim).filter(|l| !l.is_empty());
+        blocks.push(CodeBlock {
+            source: input.name.clone(),
+            kind: BlockKind::Fenced,
+            lang: lang.map(str::to_string),
+            indent: base,
+            start_line: Some(start + 1),
+            end_line: Some(end),
+            byte_start: Some(offsets[start]),
+            byte_end: Some(offsets[end]),
+            code: code.join("\n"),
+            fence_start_line: Some(i + 1),
+            closed: true,
+            ..Default::default()
+        });
+        i = j;
+    }
+
+    blocks
+}
+
+/// Extracts AsciiDoc listing (`----`) and literal (`....`) blocks, taking the
+/// language from a preceding `[source,LANG]` attribute line.
+fn parse_adoc_blocks(input: &InputSource) -> Vec<CodeBlock> {
+    let lines: Vec<&str> = input.content.lines().collect();
+    let offsets = line_offsets(&input.content);
+    let is_delimiter = |l: &str| {
+        let l = l.trim_end();
+        l.len() >= 4 && (l.bytes().all(|b| b == b'-') || l.bytes().all(|b| b == b'.'))
+    };
+    let mut blocks = Vec::new();
+    let mut lang: Option<String> = None;
+    let mut i = 0;
+
+    while i < lines.len() {
+        let line = lines[i].trim_end();
+        if let Some(attrs) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            let mut parts = attrs.split(',').map(str::trim);
+            lang = match (parts.next(), parts.next()) {
+                (Some("source" | ""), Some(l)) if !l.is_empty() => Some(l.to_string()),
+                _ => None,
+            };
+            i += 1;
+            continue;
+        }
+        // A block title (`.Title`) may sit between the attributes and the block.
+        if line.starts_with('.') && !line.starts_with("..") {
+            i += 1;
+            continue;
+        }
+        if !is_delimiter(line) {
+            lang = None;
+            i += 1;
+            continue;
+        }
+
+        let start = i + 1;
+        let close = lines[start..].iter().position(|l| l.trim_end() == line);
+        let end = close.map_or(lines.len(), |c| start + c);
+        blocks.push(CodeBlock {
+            source: input.name.clone(),
+            kind: BlockKind::Fenced,
+            lang: lang.take(),
+            start_line: Some(start + 1),
+            end_line: Some(end),
+            byte_start: Some(offsets[start]),
+            byte_end: Some(offsets[end]),
+            code: lines[start..end].join("\n"),
+            fence_start_line: Some(i + 1),
+            fence_end_line: close.map(|_| end + 1),
+            closed: close.is_some(),
+            ..Default::default()
+        });
+        i = end + 1;
+    }
+
+    blocks
+}
+
+/// A `--between` delimiter. A blank one would match every line.
+fn parse_delimiter(raw: &str) -> Result<String, String> {
+    if raw.trim().is_empty() {
+        return Err("delimiter must not be empty or blank".to_string());
+    }
+    Ok(raw.to_string())
+}
+
+/// Extracts the lines between `open` and `close` delimiter lines. Text after
+/// `open` is an info string, as after a fence. Like fences, blocks do not nest
+/// (an `open` line inside a block is content) and an unterminated block runs
+/// to the end of the input.
+fn parse_between_blocks(input: &InputSource, open: &str, close: &str) -> Vec<CodeBlock> {
+    let lines: Vec<&str> = input.content.lines().collect();
+    let offsets = line_offsets(&input.content);
+    let mut blocks = Vec::new();
+    let mut i = 0;
+
+    while i < lines.len() {
+        let Some(info) = lines[i].trim().strip_prefix(open) else {
+            i += 1;
+            continue;
+        };
+        let (lang, attrs) = parse_info_string(info.trim());
+        let start = i + 1;
+        let closing = lines[start..].iter().position(|l| l.trim() == close);
+        let end = closing.map_or(lines.len(), |c| start + c);
+        blocks.push(CodeBlock {
+            source: input.name.clone(),
+            kind: BlockKind::Fenced,
+            lang,
+            attrs,
+            indent: leading_width(lines[i]),
+            start_line: Some(start + 1),
+            end_line: Some(end),
+            byte_start: Some(offsets[start]),
+            byte_end: Some(offsets[end]),
+            code: lines[start..end].join("\n"),
+            fence_start_line: Some(i + 1),
+            fence_end_line: closing.map(|_| end + 1),
+            closed: closing.is_some(),
+            fence_open: Some(lines[i].to_string()),
+            fence_close: closing.map(|_| lines[end].to_string()),
+            ..Default::default()
+        });
+        i = end + 1;
+    }
+
+    blocks
+}
+
+/// Extracts the code cells of a Jupyter notebook, labeled with the kernel's
+/// language. Cells have no meaningful line numbers, so none are reported.
+fn parse_ipynb_blocks(input: &InputSource) -> Vec<CodeBlock> {
+    let notebook: serde_json::Value = match serde_json::from_str(&input.content) {
+        Ok(value) => value,
+        Err(e) => {
+            warn(
+                Some(&input.name),
+                None,
+                format!("{} is not a valid notebook: {e}", input.name),
+            );
+            return Vec::new();
+        }
+    };
+    let metadata = &notebook["metadata"];
+    let lang = metadata["language_info"]["name"]
+        .as_str()
+        .or_else(|| metadata["kernelspec"]["language"].as_str());
+
+    let cells = notebook["cells"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    cells
+        .iter()
+        .filter(|cell| cell["cell_type"] == "code")
+        .map(|cell| {
+            let code = match &cell["source"] {
+                serde_json::Value::Array(parts) => {
+                    parts.iter().filter_map(|p| p.as_str()).collect::<String>()
+                }
+                source => source.as_str().unwrap_or_default().to_string(),
+            };
+            CodeBlock {
+                source: input.name.clone(),
+                kind: BlockKind::Fenced,
+                lang: lang.map(str::to_string),
+                code: code.trim_end_matches('\n').to_string(),
+                closed: true,
+                ..Default::default()
+            }
+        })
+        .collect()
+}
+
+/// Parses each run of `///` or `//!` lines as its own Markdown document,
+/// mapping line numbers back to the `.rs` file. Byte spans are dropped since
+/// the comment markers are not part of the code.
+fn parse_rustdoc_blocks(input: &InputSource, parse: &ParseOptions) -> Vec<CodeBlock> {
+    let mut blocks = Vec::new();
+    let mut doc = String::new();
+    // Source line of each line in `doc`.
+    let mut lines: Vec<usize> = Vec::new();
+    let mut flush = |doc: &mut String, lines: &mut Vec<usize>| {
+        if lines.is_empty() {
+            return;
+        }
+        let virtual_input = InputSource {
+            name: input.name.clone(),
+            content: std::mem::take(doc),
+        };
+        let original = |line: usize| lines.get(line - 1).copied();
+        for mut block in parse_blocks(&virtual_input, parse) {
+            block.start_line = block.start_line.and_then(original);
+            block.end_line = block.end_line.and_then(original);
+            block.fence_start_line = block.fence_start_line.and_then(original);
+            block.fence_end_line = block.fence_end_line.and_then(original);
+            block.byte_start = None;
+            block.byte_end = None;
+            if block.kind == BlockKind::Fenced {
+                block.lang = rustdoc_lang(block.lang);
+            }
+            blocks.push(block);
+        }
+        lines.clear();
+    };
+
+    for (idx, line) in input.content.lines().enumerate() {
+        let trimmed = line.trim_start();
+        let text = trimmed
+            .strip_prefix("//!")
+            .or_else(|| trimmed.strip_prefix("///").filter(|t| !t.starts_with('/')));
+        match text {
+            Some(text) => {
+                doc.push_str(text.strip_prefix(' ').unwrap_or(text));
+                doc.push('\n');
+                lines.push(idx + 1);
+            }
+            None => flush(&mut doc, &mut lines),
+        }
+    }
+    flush(&mut doc, &mut lines);
+    blocks
+}
+
+/// Rustdoc treats untagged fences and ones tagged only with test attributes
+/// (`no_run`, `should_panic`, `edition2021`, ...) as Rust.
+fn rustdoc_lang(lang: Option<String>) -> Option<String> {
+    let is_rust = lang.as_deref().is_none_or(|lang| {
+        lang.split(',').map(str::trim).all(|tag| {
+            matches!(
+                tag,
+                "rust" | "ignore" | "no_run" | "should_panic" | "compile_fail" | "test_harness"
+            ) || tag.starts_with("edition")
+                || tag.starts_with("ignore-")
+        })
+    });
+    if is_rust {
+        Some("rust".to_string())
+    } else {
+        lang
+    }
+}
+
+fn lang_from_extension(name: &str) -> Option<String> {
+    let ext = Path::new(name)
+        .extension()?
+        .to_string_lossy()
+        .to_lowercase();
+    let lang = match ext.as_str() {
+        "rs" => "rust",
+        "py" => "python",
+        "js" | "mjs" | "cjs" => "javascript",
+        "ts" => "typescript",
+        "sh" => "bash",
+        "rb" => "ruby",
+        "yml" => "yaml",
+        "h" => "c",
+        "cc" | "cxx" | "hpp" => "cpp",
+        other => other,
+    };
+    Some(lang.to_string())
+}
+
+fn whole_file_block(input: &InputSource) -> CodeBlock {
+    let code = input.content.trim_end_matches(['\r', '\n']);
+    CodeBlock {
+        source: input.name.clone(),
+        kind: BlockKind::Fenced,
+        lang: lang_from_extension(&input.name),
+        start_line: Some(1),
+        end_line: Some(line_count(code).max(1)),
+        byte_start: Some(0),
+        byte_end: Some(code.len()),
+        closed: true,
+        code: code.to_string(),
+        ..Default::default()
+    }
+}
+
+fn parse_blocks(input: &InputSource, parse: &ParseOptions) -> Vec<CodeBlock> {
+    let mut blocks = Vec::new();
+    let mut in_fence: Option<FenceState> = None;
+    let mut headings: Vec<(usize, String)> = Vec::new();
+    let mut last_line_no = 0usize;
+    let mut offset = 0usize;
+    let tables = if parse.tables {
+        table_rows(&input.content)
+    } else {
+        BTreeMap::new()
+    };
+
+    for (idx, with_newline) in input.content.split_inclusive('\n').enumerate() {
+        let line_no = idx + 1;
+        last_line_no = line_no;
+        let line_start = offset;
+        offset += with_newline.len();
+        let raw_line = with_newline.strip_suffix('\n').unwrap_or(with_newline);
+        let raw_line = raw_line.strip_suffix('\r').unwrap_or(raw_line);
+
+        let mut fence_line = raw_line;
+        if in_fence.as_ref().is_some_and(|s| s.quoted) {
+            match strip_blockquote(raw_line) {
+                Some(inner) => fence_line = inner,
+                None => {
+                    // The blockquote ended before the fence was closed.
+                    if let Some(state) = in_fence.take() {
+                        blocks.push(state.into_block(&input.name, line_no - 1, line_start, None));
+                    }
+                }
+            }
+        }
+
+        if let Some(state) = &mut in_fence {
+            if !is_closing_fence(fence_line, state.fence_char, state.fence_len) {
+                state
+                    .buffer
+                    .push_str(strip_indent(fence_line, state.indent));
+                state.buffer.push('\n');
+                continue;
+            }
+            if let Some(state) = in_fence.take() {
+                blocks.push(state.into_block(
+                    &input.name,
+                    line_no.saturating_sub(1),
+                    line_start,
+                    Some((line_no, fence_line)),
+                ));
+            }
+            continue;
+        }
+
+        let quoted = parse
+            .blockquotes
+            .then(|| strip_blockquote(raw_line))
+            .flatten();
+        let line = quoted.unwrap_or(raw_line);
+
+        if let Some((fence_char, fence_len, info)) = parse_fence_start(line) {
+            in_fence = Some(FenceState {
+                fence_char,
+                fence_len,
+                indent: line.len() - line.trim_start().len(),
+                quoted: quoted.is_some(),
+                info,
+                opening: line.to_string(),
+                buffer: String::new(),
+                start_line: line_no + 1,
+                byte_start: offset,
+                headings: headings.clone(),
+            });
+            continue;
+        }
+
+        if let Some((level, text)) = parse_heading(line) {
+            headings.retain(|(open, _)| *open < level);
+            headings.push((level, text));
+        }
+
+        if let Some(&row) = tables.get(&line_no)
+            && quoted.is_none()
+        {
+            let mut cell_blocks = table_cell_blocks(line, line_no, line_start, &input.name, row);
+            for block in &mut cell_blocks {
+                block.heading_path = heading_path(&headings);
+                block.heading_levels = heading_levels(&headings);
+            }
+            blocks.append(&mut cell_blocks);
+            continue;
+        }
+
+        if parse.include_inline {
+            let line_offset = line_start + raw_line.len() - line.len();
+            let mut inline_blocks = parse_inline_blocks(line, line_no, line_offset, &input.name);
+            if parse.inline_debug
+                && let Some(report) = inline_debug_line(line, inline_blocks.len())
+            {
+                eprintln!("{}:{line_no} {report}", input.name);
+            }
+            for block in &mut inline_blocks {
+                block.heading_path = heading_path(&headings);
+                block.heading_levels = heading_levels(&headings);
+            }
+            blocks.append(&mut inline_blocks);
+        }
+    }
+
+    if let Some(state) = in_fence {
+        // Unterminated fence; treat rest of file as the block.
+        blocks.push(state.into_block(&input.name, last_line_no, offset, None));
+    }
+
+    blocks
+}
+
+static TABLE_SEPARATOR: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^\s*\|?\s*:?-+:?\s*(\|\s*:?-+:?\s*)*\|?\s*$").unwrap());
+
+/// Maps the lines of GitHub-style tables (a piped header row followed by a
+/// `|---|:--:|` separator) to their row: 0 for the header, body rows from 1.
+/// Separator rows are left out, and a table ends at a line without a pipe.
+fn table_rows(content: &str) -> BTreeMap<usize, usize> {
+    let lines: Vec<&str> = content.lines().collect();
+    let mut rows = BTreeMap::new();
+    let mut i = 0;
+    while i + 1 < lines.len() {
+        let separator = lines[i + 1];
+        if !(lines[i].contains('|')
+            && separator.contains('|')
+            && TABLE_SEPARATOR.is_match(separator))
+        {
+            i += 1;
+            continue;
+        }
+        rows.insert(i + 1, 0);
+        let mut next = i + 2;
+        while next < lines.len() && lines[next].contains('|') {
+            rows.insert(next + 1, next - i - 1);
+            next += 1;
+        }
+        i = next;
+    }
+    rows
+}
+
+/// The inline code spans of one table row, tagged with their cell. Cells
+/// split on pipes not escaped with a backslash; `\|` inside a span is a
+/// literal pipe, as on GitHub.
+fn table_cell_blocks(
+    line: &str,
+    line_no: usize,
+    offset: usize,
+    source: &str,
+    row: usize,
+) -> Vec<CodeBlock> {
+    let body = line.trim_end();
+    let bytes = body.as_bytes();
+    let mut first = body.len() - body.trim_start().len();
+    if bytes.get(first) == Some(&b'|') {
+        first += 1;
+    }
+
+    let mut blocks = Vec::new();
+    let mut col = 0;
+    let mut escaped = false;
+    let mut cell_start = first;
+    for i in first..=bytes.len() {
+        let at_end = i == bytes.len();
+        if !at_end && escaped {
+            escaped = false;
+            continue;
+        }
+        if !at_end && bytes[i] == b'\\' {
+            escaped = true;
+            continue;
+        }
+        if !at_end && bytes[i] != b'|' {
+            continue;
+        }
+        if at_end && cell_start == i {
+            break;
+        }
+        let cell = &body[cell_start..i];
+        for mut block in parse_inline_blocks(cell, line_no, offset + cell_start, source) {
+            block.code = block.code.replace("\\|", "|");
+            block.row = Some(row);
+            block.col = Some(col);
+            blocks.push(block);
+        }
+        col += 1;
+        cell_start = i + 1;
+    }
+    blocks
+}
+
+#[derive(Debug)]
+struct FenceState {
+    fence_char: char,
+    fence_len: usize,
+    indent: usize,
+    /// Opened inside a `>` blockquote; content lines carry the marker too.
+    quoted: bool,
+    info: Option<String>,
+    opening: String,
+    buffer: String,
+    start_line: usize,
+    byte_start: usize,
+    headings: Vec<(usize, String)>,
+}
+
+impl FenceState {
+    fn into_block(
+        self,
+        source: &str,
+        end_line: usize,
+        byte_end: usize,
+        closing: Option<(usize, &str)>,
+    ) -> CodeBlock {
+        let code = self.buffer.trim_end_matches('\n');
+        let trimmed = self.buffer.len() - code.len();
+        // The last content line's own terminator is not a blank line.
+        let trailing_newlines = if code.is_empty() {
+            trimmed
+        } else {
+            trimmed.saturating_sub(1)
+        };
+
+        let (lang, attrs) = parse_info_string(self.info.as_deref().unwrap_or_default());
+
+        CodeBlock {
+            source: source.to_string(),
+            kind: BlockKind::Fenced,
+            lang,
+            attrs,
+            indent: self.indent,
+            start_line: Some(self.start_line),
+            end_line: Some(end_line),
+            byte_start: Some(self.byte_start),
+            byte_end: Some(byte_end),
+            code: code.to_string(),
+            trailing_newlines,
+            heading_path: heading_path(&self.headings),
+            heading_levels: heading_levels(&self.headings),
+            fence_start_line: Some(self.start_line - 1),
+            fence_end_line: closing.map(|(line_no, _)| line_no),
+            closed: closing.is_some(),
+            fence_char: Some(self.fence_char),
+            fence_len: Some(self.fence_len),
+            fence_open: Some(self.opening),
+            fence_close: closing.map(|(_, line)| line.to_string()),
+            ..Default::default()
+        }
+    }
+}
+
+/// Strips a single-level blockquote marker (`> `) from a line.
+fn strip_blockquote(line: &str) -> Option<&str> {
+    let trimmed = line.trim_start_matches(' ');
+    if line.len() - trimmed.len() > 3 {
+        return None;
+    }
+    let inner = trimmed.strip_prefix('>')?;
+    Some(inner.strip_prefix(' ').unwrap_or(inner))
+}
+
+/// Removes up to `indent` leading whitespace characters, mirroring how an
+/// indented opening fence shifts the margin of its content.
+fn strip_indent(line: &str, indent: usize) -> &str {
+    let strip = line
+        .char_indices()
+        .take(indent)
+        .take_while(|(_, c)| *c == ' ' || *c == '\t')
+        .count();
+    &line[strip..]
+}
+
+/// Splits an info string such as `rust {example=api .runnable #intro}` into
+/// the language and its attributes. Bare words become keys with empty values,
+/// `.class` tokens accumulate under `class` and `#id` sets `id`. A pandoc-style
+/// `{.rust}` with no leading language takes the first class as the language.
+fn parse_info_string(info: &str) -> (Option<String>, BTreeMap<String, String>) {
+    let mut tokens = tokenize_info(info).into_iter();
+    let mut lang = None;
+    let mut attrs = BTreeMap::new();
+
+    let first = tokens.next();
+    let rest: Vec<(String, bool)> = match first {
+        Some((token, false)) => {
+            lang = Some(token);
+            tokens.collect()
+        }
+        Some(braced) => std::iter::once(braced).chain(tokens).collect(),
+        None => Vec::new(),
+    };
+
+    for (token, _) in rest {
+        if let Some(class) = token.strip_prefix('.') {
+            attrs
+                .entry("class".to_string())
+                .and_modify(|c: &mut String| {
+                    c.push(' ');
+                    c.push_str(class);
+                })
+                .or_insert_with(|| class.to_string());
+            if lang.is_none() {
+                lang = Some(class.to_string());
+            }
+        } else if let Some(id) = token.strip_prefix('#') {
+            attrs.insert("id".to_string(), id.to_string());
+        } else if let Some((key, value)) = token.split_once('=') {
+            attrs.insert(key.to_string(), value.trim_matches('"').to_string());
+        } else {
+            attrs.insert(token, String::new());
+        }
+    }
+
+    (lang, attrs)
+}
+
+/// Splits on whitespace, keeping double-quoted values together and marking
+/// tokens that appeared inside `{...}`.
+fn tokenize_info(info: &str) -> Vec<(String, bool)> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+    let mut in_braces = false;
+    let mut current_braced = false;
+
+    for c in info.chars() {
+        if !in_quotes && (c.is_whitespace() || c == '{' || c == '}') {
+            if !current.is_empty() {
+                tokens.push((std::mem::take(&mut current), current_braced));
+            }
+            match c {
+                '{' => in_braces = true,
+                '}' => in_braces = false,
+                _ => {}
+            }
+            continue;
+        }
+
+        if current.is_empty() {
+            current_braced = in_braces;
+        }
+        if c == '"' {
+            in_quotes = !in_quotes;
+        }
+        current.push(c);
+    }
+    if !current.is_empty() {
+        tokens.push((current, current_braced));
+    }
+
+    tokens
+}
+
+/// `KEY=VALUE` matches when the attribute equals VALUE (or, for `class`,
+/// contains it); a bare `KEY` matches when the attribute is present.
+fn matches_attr(block: &CodeBlock, filter: &str) -> bool {
+    match filter.split_once('=') {
+        Some(("class", wanted)) => block
+            .attrs
+            .get("class")
+            .is_some_and(|c| c.split(' ').any(|class| class == wanted)),
+        Some((key, value)) => block.attrs.get(key).is_some_and(|v| v == value),
+        None => block.attrs.contains_key(filter),
+    }
+}
+
+fn heading_path(headings: &[(usize, String)]) -> Vec<String> {
+    headings.iter().map(|(_, text)| text.clone()).collect()
+}
+
+fn heading_levels(headings: &[(usize, String)]) -> Vec<usize> {
+    headings.iter().map(|(level, _)| *level).collect()
+}
+
+/// Recognizes ATX headings (`## Title ##`), returning the level and text.
+fn parse_heading(line: &str) -> Option<(usize, String)> {
+    let indent = line.len() - line.trim_start_matches(' ').len();
+    if indent > 3 {
+        return None;
+    }
+
+    let trimmed = &line[indent..];
+    let level = trimmed.chars().take_while(|c| *c == '#').count();
+    if level == 0 || level > 6 {
+        return None;
+    }
+
+    let rest = &trimmed[level..];
+    if !rest.is_empty() && !rest.starts_with([' ', '\t']) {
+        return None;
+    }
+
+    let text = rest.trim().trim_end_matches('#').trim_end().to_string();
+    Some((level, text))
+}
+
+fn parse_fence_start(line: &str) -> Option<(char, usize, Option<String>)> {
+    let trimmed = line.trim_start();
+    let (fence_char, fence_len) = if trimmed.starts_with("```") {
+        ('`', trimmed.chars().take_while(|c| *c == '`').count())
+    } else if trimmed.starts_with("~~~") {
+        ('~', trimmed.chars().take_while(|c| *c == '~').count())
+    } else {
+        return None;
+    };
+
+    let lang = trimmed
+        .chars()
+        .skip(fence_len)
+        .collect::<String>()
+        .trim()
+        .to_string();
+    let lang = if lang.is_empty() { None } else { Some(lang) };
+
+    Some((fence_char, fence_len, lang))
+}
+
+/// A closing fence is a run of the opening character at least as long as the
+/// opening fence, followed by nothing but whitespace.
+fn is_closing_fence(line: &str, fence_char: char, fence_len: usize) -> bool {
+    let trimmed = line.trim_start();
+    let prefix_len = trimmed.chars().take_while(|c| *c == fence_char).count();
+    let rest = &trimmed[prefix_len * fence_char.len_utf8()..];
+    prefix_len >= fence_len && prefix_len >= 3 && rest.trim().is_empty()
+}
+
+/// Describes the backtick runs of `line` and how many spans they formed, or
+/// `None` for lines without backticks.
+fn inline_debug_line(line: &str, spans: usize) -> Option<String> {
+    let mut runs = Vec::new();
+    let mut run = 0;
+    for c in line.chars().chain(std::iter::once('\n')) {
+        if c == '`' {
+            run += 1;
+        } else if run > 0 {
+            runs.push(run.to_string());
+            run = 0;
+        }
+    }
+    if runs.is_empty() {
+        return None;
+    }
+    let noun = if spans == 1 { "span" } else { "spans" };
+    Some(format!("ticks=[{}] -> {spans} {noun}", runs.join(",")))
+}
+
+/// `offset` is the byte position of `line` within its source.
+fn parse_inline_blocks(line: &str, line_no: usize, offset: usize, source: &str) -> Vec<CodeBlock> {
+    let mut blocks = Vec::new();
+    let mut start_tick: Option<usize> = None;
+    let mut start_idx: Option<usize> = None;
+    // Backticks are ASCII, so slicing at their byte offsets always lands on
+    // char boundaries even when the surrounding text is multibyte.
+    let bytes = line.as_bytes();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'`' {
+            let mut tick_len = 1;
+            while i + tick_len < bytes.len() && bytes[i + tick_len] == b'`' {
+                tick_len += 1;
+            }
+            if start_tick.is_none() {
+                start_tick = Some(tick_len);
+                start_idx = Some(i + tick_len);
+            } else if let Some(open_ticks) = start_tick
+                && tick_len == open_ticks
+            {
+                let content_start = start_idx.unwrap_or(i);
+                let content = line[content_start..i].to_string();
+                if !content.is_empty() {
+                    blocks.push(CodeBlock {
+                        source: source.to_string(),
+                        kind: BlockKind::Inline,
+                        start_line: Some(line_no),
+                        end_line: Some(line_no),
+                        byte_start: Some(offset + content_start),
+                        byte_end: Some(offset + i),
+                        code: content,
+                        closed: true,
+                        ..Default::default()
+                    });
+                }
+                start_tick = None;
+                start_idx = None;
+            }
+            i += tick_len;
+        } else {
+            i += 1;
+        }
+    }
+
+    blocks
+}
+
+fn matches_lang(block: &CodeBlock, lang: &str, aliases: &BTreeMap<String, String>) -> bool {
+    block
+        .lang
+        .as_deref()
+        .map(|b| canonical_lang(b, aliases).eq_ignore_ascii_case(&canonical_lang(lang, aliases)))
+        .unwrap_or(false)
+}
+
+/// Classifies a block by language: configured `categories` win over the
+/// built-in table, and anything unlisted is `code`.
+fn block_category(
+    block: &CodeBlock,
+    categories: &BTreeMap<String, String>,
+    aliases: &BTreeMap<String, String>,
+) -> String {
+    let Some(lang) = block.lang.as_deref() else {
+        return "code".to_string();
+    };
+    let lang = canonical_lang(lang, aliases);
+    if let Some(category) = categories.get(&lang) {
+        return category.clone();
+    }
+    let builtin = match lang.as_str() {
+        "mermaid" | "graphviz" | "dot" | "plantuml" | "puml" | "ditaa" | "d2" | "svgbob" => {
+            "diagram"
+        }
+        "math" | "latex" | "tex" | "katex" | "asciimath" => "math",
+        _ => "code",
+    };
+    builtin.to_string()
+}
+
+fn lang_violations(
+    blocks: &[CodeBlock],
+    allowed: &[String],
+    aliases: &BTreeMap<String, String>,
+) -> Vec<String> {
+    blocks
+        .iter()
+        .filter(|b| b.kind == BlockKind::Fenced)
+        .filter_map(|b| {
+            let line = b.fence_start_line.unwrap_or(0);
+            match &b.lang {
+                None => Some(format!(
+                    "{}:{}: fenced block has no language",
+                    b.source, line
+                )),
+                Some(lang)
+                    if !allowed.is_empty()
+                        && !allowed.iter().any(|a| matches_lang(b, a, aliases)) =>
+                {
+                    Some(format!(
+                        "{}:{}: language '{}' is not allowed",
+                        b.source, line, lang
+                    ))
+                }
+                Some(_) => None,
+            }
+        })
+        .collect()
+}
+
+fn canonical_lang(lang: &str, aliases: &BTreeMap<String, String>) -> String {
+    let lower = lang.to_lowercase();
+    match aliases.get(&lower) {
+        Some(target) => target.to_lowercase(),
+        None => lower,
+    }
+}
+
+/// `source:line` reports for block lines over `max` characters.
+fn long_lines(blocks: &[CodeBlock], max: usize, ignore_urls: bool) -> Vec<String> {
+    let mut violations = Vec::new();
+    for block in blocks {
+        let first = block.start_line.unwrap_or(1);
+        for (offset, line) in block.code.lines().enumerate() {
+            let len = line.chars().count();
+            if len <= max || (ignore_urls && has_url(line)) {
+                continue;
+            }
+            violations.push(format!(
+                "{}:{}: line is {len} characters (max {max})",
+                block.source,
+                first + offset
+            ));
+        }
+    }
+    violations
+}
+
+/// `source:line` reports for fences not using `required`, or, without it,
+/// not using the delimiter of their document's first fence.
+fn mixed_fences(blocks: &[CodeBlock], required: Option<char>) -> Vec<String> {
+    let name = |c: char| if c == '~' { "tilde" } else { "backtick" };
+    let mut expected: BTreeMap<&str, char> = BTreeMap::new();
+    let mut violations = Vec::new();
+    for block in blocks {
+        let Some(fence) = block.fence_char else {
+            continue;
+        };
+        let want = required.unwrap_or_else(|| *expected.entry(&block.source).or_insert(fence));
+        if fence != want {
+            let rule = if required.is_some() {
+                "required"
+            } else {
+                "document uses"
+            };
+            violations.push(format!(
+                "{}:{}: {} fence ({rule} {} fences)",
+                block.source,
+                block.fence_start_line.unwrap_or_default(),
+                name(fence),
+                name(want)
+            ));
+        }
+    }
+    violations
+}
+
+/// `source:line` reports for fences opened with other than `len` characters.
+fn wrong_fence_lengths(blocks: &[CodeBlock], len: usize) -> Vec<String> {
+    blocks
+        .iter()
+        .filter_map(|b| Some((b, b.fence_len?)))
+        .filter(|(_, actual)| *actual != len)
+        .map(|(b, actual)| {
+            format!(
+                "{}:{}: fence is {actual} characters long (required {len})",
+                b.source,
+                b.fence_start_line.unwrap_or_default()
+            )
+        })
+        .collect()
+}
+
+/// `source:line` reports for block lines holding a tab; prose is never checked.
+fn tab_lines(blocks: &[CodeBlock]) -> Vec<String> {
+    let mut violations = Vec::new();
+    for block in blocks {
+        let first = block.start_line.unwrap_or(1);
+        for (offset, line) in block.code.lines().enumerate() {
+            if let Some(column) = line.find('\t') {
+                violations.push(format!(
+                    "{}:{}: tab character at column {}",
+                    block.source,
+                    first + offset,
+                    line[..column].chars().count() + 1
+                ));
+            }
+        }
+    }
+    violations
+}
+
+fn has_url(line: &str) -> bool {
+    line.contains("http://") || line.contains("https://")
+}
+
+/// Whether source line `line` falls within the block, counting its fences.
+fn contains_line(block: &CodeBlock, line: usize) -> bool {
+    let first = block.fence_start_line.or(block.start_line);
+    let last = block.fence_end_line.or(block.end_line);
+    matches!((first, last), (Some(first), Some(last)) if (first..=last).contains(&line))
+}
+
+/// Matches a source by its full name or file name, literally or as a glob.
+fn source_matcher(
+    pattern: &str,
+    case_insensitive: bool,
+) -> Result<impl Fn(&str) -> bool, Box<dyn std::error::Error>> {
+    let glob = GlobBuilder::new(pattern)
+        .case_insensitive(case_insensitive)
+        .build()?
+        .compile_matcher();
+    let pattern = pattern.to_string();
+    Ok(move |source: &str| {
+        let file_name = Path::new(source)
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        source == pattern
+            || file_name == pattern
+            || glob.is_match(source)
+            || glob.is_match(&file_name)
+    })
+}
+
+/// Stable-sorts blocks by `key` inside each source, keeping sources in the
+/// order they first appear. Indices are left untouched.
+fn sort_within_source(blocks: &mut [CodeBlock], key: SortKey) {
+    let mut source_rank: BTreeMap<String, usize> = BTreeMap::new();
+    for block in blocks.iter() {
+        let next = source_rank.len();
+        source_rank.entry(block.source.clone()).or_insert(next);
+    }
+    blocks.sort_by(|a, b| {
+        source_rank[&a.source]
+            .cmp(&source_rank[&b.source])
+            .then_with(|| match key {
+                SortKey::Lang => match (&a.lang, &b.lang) {
+                    (Some(x), Some(y)) => x.to_lowercase().cmp(&y.to_lowercase()),
+                    (x, y) => y.is_some().cmp(&x.is_some()),
+                },
+                SortKey::Size => line_count(&a.code).cmp(&line_count(&b.code)),
+            })
+    });
+}
+
+/// Keeps the first `limit` blocks of each source; indices are left untouched.
+fn cap_per_source(blocks: Vec<CodeBlock>, limit: usize) -> Vec<CodeBlock> {
+    let mut taken: BTreeMap<String, usize> = BTreeMap::new();
+    blocks
+        .into_iter()
+        .filter(|b| {
+            let count = taken.entry(b.source.clone()).or_default();
+            *count += 1;
+            *count <= limit
+        })
+        .collect()
+}
+
+/// Keeps the first block of each canonical language, unlabeled blocks
+/// forming one more group; order and indices are left untouched.
+fn first_per_lang(blocks: Vec<CodeBlock>, aliases: &BTreeMap<String, String>) -> Vec<CodeBlock> {
+    let mut seen: BTreeSet<Option<String>> = BTreeSet::new();
+    blocks
+        .into_iter()
+        .filter(|b| seen.insert(b.lang.as_deref().map(|l| canonical_lang(l, aliases))))
+        .collect()
+}
+
+/// A parsed `--where` expression; `&&` binds tighter than `||`.
+#[derive(Debug, PartialEq)]
+enum WhereExpr {
+    Compare {
+        field: WhereField,
+        op: CompareOp,
+        value: String,
+    },
+    And(Box<WhereExpr>, Box<WhereExpr>),
+    Or(Box<WhereExpr>, Box<WhereExpr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum WhereField {
+    Lang,
+    Kind,
+    Lines,
+    Source,
+    Index,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+}
+
+impl WhereField {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "lang" => Some(WhereField::Lang),
+            "kind" => Some(WhereField::Kind),
+            "lines" => Some(WhereField::Lines),
+            "source" => Some(WhereField::Source),
+            "index" => Some(WhereField::Index),
+            _ => None,
+        }
+    }
+
+    fn is_numeric(self) -> bool {
+        matches!(self, WhereField::Lines | WhereField::Index)
+    }
+}
+
+impl WhereExpr {
+    fn matches(&self, block: &CodeBlock, aliases: &BTreeMap<String, String>) -> bool {
+        match self {
+            WhereExpr::And(a, b) => a.matches(block, aliases) && b.matches(block, aliases),
+            WhereExpr::Or(a, b) => a.matches(block, aliases) || b.matches(block, aliases),
+            WhereExpr::Compare { field, op, value } => {
+                let ordering = match field {
+                    WhereField::Lines | WhereField::Index => {
+                        let actual = if *field == WhereField::Lines {
+                            line_count(&block.code)
+                        } else {
+                            block.index
+                        };
+                        // Checked when parsing.
+                        actual.cmp(&value.parse::<usize>().unwrap_or_default())
+                    }
+                    WhereField::Lang => {
+                        let actual = block.lang.as_deref().unwrap_or_default();
+                        canonical_lang(actual, aliases).cmp(&canonical_lang(value, aliases))
+                    }
+                    WhereField::Kind => block.kind.name().cmp(value.as_str()),
+                    WhereField::Source => block.source.as_str().cmp(value.as_str()),
+                };
+                match op {
+                    CompareOp::Eq => ordering.is_eq(),
+                    CompareOp::Ne => ordering.is_ne(),
+                    CompareOp::Lt => ordering.is_lt(),
+                    CompareOp::Gt => ordering.is_gt(),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum WhereToken {
+    Word(String),
+    Quoted(String),
+    Op(&'static str),
+}
+
+impl std::fmt::Display for WhereToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WhereToken::Word(word) => write!(f, "`{word}`"),
+            WhereToken::Quoted(text) => write!(f, "`\"{text}\"`"),
+            WhereToken::Op(op) => write!(f, "`{op}`"),
+        }
+    }
+}
+
+const WHERE_OPS: [&str; 8] = ["==", "!=", "&&", "||", "<", ">", "(", ")"];
+
+/// Splits a `--where` expression into tokens paired with their 1-based column.
+fn tokenize_where(raw: &str) -> Result<Vec<(usize, WhereToken)>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = raw;
+    while let Some(start) = rest.find(|c: char| !c.is_whitespace()) {
+        rest = &rest[start..];
+        let column = raw.len() - rest.len() + 1;
+        if let Some(op) = WHERE_OPS.into_iter().find(|op| rest.starts_with(op)) {
+            tokens.push((column, WhereToken::Op(op)));
+            rest = &rest[op.len()..];
+        } else if let Some(quote) = rest.chars().next().filter(|c| *c == '"' || *c == '\'') {
+            let body = &rest[1..];
+            let end = body
+                .find(quote)
+                .ok_or_else(|| format!("unterminated string at column {column}"))?;
+            tokens.push((column, WhereToken::Quoted(body[..end].to_string())));
+            rest = &body[end + 1..];
+        } else {
+            let end = rest
+                .find(|c: char| c.is_whitespace() || "=!<>&|()\"'".contains(c))
+                .unwrap_or(rest.len());
+            if end == 0 {
+                return Err(format!(
+                    "unexpected `{}` at column {column}",
+                    &rest[..rest.chars().next().map_or(1, char::len_utf8)]
+                ));
+            }
+            tokens.push((column, WhereToken::Word(rest[..end].to_string())));
+            rest = &rest[end..];
+        }
+    }
+    Ok(tokens)
+}
+
+/// Parses a `--where` expression such as `lang==rust && lines>10`.
+fn parse_where(raw: &str) -> Result<WhereExpr, String> {
+    let tokens = tokenize_where(raw)?;
+    let mut parser = WhereParser { tokens, pos: 0 };
+    let expr = parser.or()?;
+    match parser.tokens.get(parser.pos) {
+        Some((column, token)) => Err(format!("unexpected {token} at column {column}")),
+        None => Ok(expr),
+    }
+}
+
+struct WhereParser {
+    tokens: Vec<(usize, WhereToken)>,
+    pos: usize,
+}
+
+impl WhereParser {
+    fn next(&mut self, expected: &str) -> Result<&(usize, WhereToken), String> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .ok_or_else(|| format!("expected {expected} at end of expression"))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn eat(&mut self, op: &str) -> bool {
+        let found = matches!(self.tokens.get(self.pos), Some((_, WhereToken::Op(o))) if *o == op);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn or(&mut self) -> Result<WhereExpr, String> {
+        let mut expr = self.and()?;
+        while self.eat("||") {
+            expr = WhereExpr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<WhereExpr, String> {
+        let mut expr = self.comparison()?;
+        while self.eat("&&") {
+            expr = WhereExpr::And(Box::new(expr), Box::new(self.comparison()?));
+        }
+        Ok(expr)
+    }
+
+    fn comparison(&mut self) -> Result<WhereExpr, String> {
+        if self.eat("(") {
+            let expr = self.or()?;
+            return match self.next("`)`")? {
+                (_, WhereToken::Op(")")) => Ok(expr),
+                (column, token) => {
+                    Err(format!("expected `)` but found {token} at column {column}"))
+                }
+            };
+        }
+        let field = match self.next("a field")? {
+            (column, WhereToken::Word(name)) => WhereField::from_name(name).ok_or_else(|| {
+                format!(
+                    "unknown field `{name}` at column {column} (expected lang, kind, lines, source or index)"
+                )
+            })?,
+            (column, token) => return Err(format!("expected a field but found {token} at column {column}")),
+        };
+        let op = match self.next("an operator")? {
+            (column, WhereToken::Op(op @ ("<" | ">"))) if !field.is_numeric() => {
+                return Err(format!(
+                    "`{op}` at column {column} compares numbers; use it with lines or index"
+                ));
+            }
+            (_, WhereToken::Op("==")) => CompareOp::Eq,
+            (_, WhereToken::Op("!=")) => CompareOp::Ne,
+            (_, WhereToken::Op("<")) => CompareOp::Lt,
+            (_, WhereToken::Op(">")) => CompareOp::Gt,
+            (column, token) => {
+                return Err(format!(
+                    "expected an operator but found {token} at column {column}"
+                ));
+            }
+        };
+        let (column, value) = match self.next("a value")? {
+            (column, WhereToken::Word(value) | WhereToken::Quoted(value)) => {
+                (*column, value.clone())
+            }
+            (column, token) => {
+                return Err(format!(
+                    "expected a value but found {token} at column {column}"
+                ));
+            }
+        };
+        if field.is_numeric() && value.parse::<usize>().is_err() {
+            return Err(format!(
+                "expected a number but found `{value}` at column {column}"
+            ));
+        }
+        Ok(WhereExpr::Compare { field, op, value })
+    }
+}
+
+/// Keeps `n` blocks drawn uniformly without replacement, in their original
+/// order. A `seed` makes the draw reproducible.
+fn sample_blocks(blocks: Vec<CodeBlock>, n: usize, seed: Option<u64>) -> Vec<CodeBlock> {
+    if n >= blocks.len() {
+        return blocks;
+    }
+    let mut rng = match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => rand::make_rng(),
+    };
+    let picked: BTreeSet<usize> = rand::seq::index::sample(&mut rng, blocks.len(), n)
+        .into_iter()
+        .collect();
+    blocks
+        .into_iter()
+        .enumerate()
+        .filter(|(position, _)| picked.contains(position))
+        .map(|(_, block)| block)
+        .collect()
+}
+
+fn is_blank_block(block: &CodeBlock) -> bool {
+    block.code.trim().is_empty()
+}
+
+fn strip_leading_blank(block: &mut CodeBlock, source: Option<&str>) {
+    let Some((first, rest)) = block.code.split_once('\n') else {
+        return;
+    };
+    if first.trim().is_empty() {
+        block.code = rest.to_string();
+        block.start_line = block.start_line.map(|l| l + 1);
+        narrow_span(block, source, 1, usize::MAX);
+    }
+}
+
+/// Narrows the byte span to the `keep` content lines after the first `skip`,
+/// following a line-based cut of the code. Without the source text to
+/// measure lines in, the span is dropped rather than left stale.
+fn narrow_span(block: &mut CodeBlock, source: Option<&str>, skip: usize, keep: usize) {
+    let (Some(start), Some(end)) = (block.byte_start, block.byte_end) else {
+        return;
+    };
+    let Some(raw) = source.and_then(|s| s.get(start..end)) else {
+        block.byte_start = None;
+        block.byte_end = None;
+        return;
+    };
+    let mut lines = raw.split_inclusive('\n');
+    let start = start + lines.by_ref().take(skip).map(str::len).sum::<usize>();
+    let end = start + lines.take(keep).map(str::len).sum::<usize>();
+    block.byte_start = Some(start);
+    block.byte_end = Some(end);
+}
+
+fn dedent(code: &str) -> String {
+    let mut common: Option<&str> = None;
+    for line in code.lines().filter(|l| !l.trim().is_empty()) {
+        let indent = &line[..line.len() - line.trim_start().len()];
+        common = Some(match common {
+            None => indent,
+            Some(prev) => {
+                let shared: usize = prev
+                    .chars()
+                    .zip(indent.chars())
+                    .take_while(|(a, b)| a == b)
+                    .map(|(a, _)| a.len_utf8())
+                    .sum();
+                &prev[..shared]
+            }
+        });
+    }
+
+    let prefix = common.unwrap_or("");
+    if prefix.is_empty() {
+        return code.to_string();
+    }
+    code.lines()
+        .map(|l| l.strip_prefix(prefix).unwrap_or_else(|| l.trim_start()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Runs `cmd` through `sh -c` with `code` on stdin and returns its stdout.
+fn run_transform(cmd: &str, code: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let mut child = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()?;
+
+    // Feed stdin from another thread so a filter that writes before it has
+    // read everything cannot deadlock against us.
+    let mut stdin = child.stdin.take().ok_or("transform has no stdin")?;
+    let input = format!("{code}\n");
+    let writer = std::thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let output = child.wait_with_output()?;
+    // A filter may exit without reading its input; that surfaces as a broken pipe.
+    let _ = writer.join();
+
+    if !output.status.success() {
+        return Err(format!("`{cmd}` exited with {}", output.status).into());
+    }
+    let stdout = String::from_utf8(output.stdout)
+        .map_err(|_| format!("`{cmd}` produced output that is not UTF-8"))?;
+    Ok(stdout.trim_end_matches('\n').to_string())
+}
+
+/// Imports of one block, as emitted by `--imports --json`.
+#[derive(Debug, Serialize)]
+struct BlockImports<'a> {
+    index: usize,
+    source: &'a str,
+    lang: Option<&'a str>,
+    imports: Vec<String>,
+}
+
+static RUST_IMPORT: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?m)^\s*(?:pub(?:\([^)]*\))?\s+)?(?:use\s+([^;]+);|extern\s+crate\s+(\w+))")
+        .unwrap()
+});
+static PYTHON_IMPORT: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?m)^\s*(?:from\s+(\S+)\s+import\b|import\s+([^#\n]+))").unwrap()
+});
+static JS_IMPORT: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r#"(?m)^\s*import\s+(?:[^'"]*?\s+from\s+)?['"]([^'"]+)['"]|require\(\s*['"]([^'"]+)['"]\s*\)"#,
+    )
+    .unwrap()
+});
+
+/// Module paths a block imports, found by a per-language regex. Blocks in
+/// other languages have none.
+fn block_imports(block: &CodeBlock, aliases: &BTreeMap<String, String>) -> Vec<String> {
+    let Some(lang) = block.lang.as_deref() else {
+        return Vec::new();
+    };
+    let lang = canonical_lang(lang, aliases);
+    let python = matches!(lang.as_str(), "python" | "py");
+    let regex = match lang.as_str() {
+        "rust" | "rs" => &*RUST_IMPORT,
+        _ if python => &*PYTHON_IMPORT,
+        "javascript" | "js" | "jsx" | "typescript" | "ts" | "tsx" | "mjs" => &*JS_IMPORT,
+        _ => return Vec::new(),
+    };
+
+    let mut imports = BTreeSet::new();
+    for caps in regex.captures_iter(&block.code) {
+        let Some(found) = caps.get(1).or_else(|| caps.get(2)) else {
+            continue;
+        };
+        // `import a, b as c` lists several modules at once.
+        if python && caps.get(2).is_some() {
+            for module in found.as_str().split(',') {
+                let name = module.split_whitespace().next().unwrap_or_default();
+                if !name.is_empty() {
+                    imports.insert(name.to_string());
+                }
+            }
+        } else {
+            imports.insert(
+                found
+                    .as_str()
+                    .split_whitespace()
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            );
+        }
+    }
+    imports.into_iter().collect()
+}
+
+fn is_session_lang(lang: Option<&str>) -> bool {
+    lang.is_some_and(|l| {
+        ["console", "shell-session", "shellsession"]
+            .iter()
+            .any(|s| l.eq_ignore_ascii_case(s))
+    })
+}
+
+fn is_shell_lang(lang: Option<&str>) -> bool {
+    is_session_lang(lang)
+        || lang.is_some_and(|l| {
+            ["sh", "bash", "zsh", "ksh", "fish", "shell"]
+                .iter()
+                .any(|s| l.eq_ignore_ascii_case(s))
+        })
+}
+
+/// The `--strip-prompt` characters for a block. In a plain script `#` opens
+/// a comment, so only session transcripts treat it as the root prompt.
+fn prompt_chars(lang: Option<&str>, chars: &str) -> String {
+    if is_session_lang(lang) {
+        chars.to_string()
+    } else {
+        chars.replace('#', "")
+    }
+}
+
+/// Removes a leading `<c> ` prompt from each line, for any `c` in `chars`.
+fn strip_prompts(code: &str, chars: &str) -> String {
+    code.split('\n')
+        .map(|line| {
+            let mut rest = line.chars();
+            match (rest.next(), rest.next()) {
+                (Some(c), Some(' ')) if chars.contains(c) => &line[c.len_utf8() + 1..],
+                _ => line,
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Runnable code for Rust or Python doc examples, `None` for other languages.
+fn strip_doctest(lang: Option<&str>, code: &str) -> Option<String> {
+    let is = |names: &[&str]| lang.is_some_and(|l| names.iter().any(|n| l.eq_ignore_ascii_case(n)));
+    if is(&["rust", "rs"]) {
+        Some(unhide_rust_lines(code))
+    } else if is(&["python", "py", "python3", "pycon", "doctest"]) {
+        Some(strip_python_prompts(code))
+    } else {
+        None
+    }
+}
+
+/// Keeps rustdoc's hidden lines without their marker: `# x` becomes `x`, a
+/// lone `#` an empty line, and the `##` escape a literal `#`.
+fn unhide_rust_lines(code: &str) -> String {
+    code.split('\n')
+        .map(|line| {
+            let trimmed = line.trim_start();
+            let indent = &line[..line.len() - trimmed.len()];
+            if trimmed == "#" {
+                String::new()
+            } else if let Some(rest) = trimmed.strip_prefix("# ") {
+                format!("{indent}{rest}")
+            } else if let Some(rest) = trimmed.strip_prefix("##") {
+                format!("{indent}#{rest}")
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Strips `>>> ` and `... ` prompts from a Python doctest and drops the
+/// expected-output lines between them. Code without prompts is unchanged.
+fn strip_python_prompts(code: &str) -> String {
+    if !code
+        .lines()
+        .any(|line| line.starts_with(">>>") && python_prompt(line).is_some())
+    {
+        return code.to_string();
+    }
+    code.lines()
+        .filter_map(python_prompt)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The code after a `>>>` or `...` prompt, if `line` starts with one.
+fn python_prompt(line: &str) -> Option<&str> {
+    [">>>", "..."].into_iter().find_map(|p| {
+        let rest = line.strip_prefix(p)?;
+        if rest.is_empty() {
+            Some(rest)
+        } else {
+            rest.strip_prefix(' ')
+        }
+    })
+}
+
+/// Extracts prompt-prefixed lines (`$ cmd`, `# cmd`) from a shell session,
+/// following trailing-backslash continuations. Output lines are dropped.
+fn session_commands(code: &str) -> Vec<String> {
+    let mut commands: Vec<String> = Vec::new();
+    let mut continuing = false;
+
+    for line in code.lines() {
+        if continuing {
+            if let Some(last) = commands.last_mut() {
+                last.push('\n');
+                last.push_str(line);
+            }
+        } else if let Some(command) = line.strip_prefix("$ ").or_else(|| line.strip_prefix("# ")) {
+            commands.push(command.to_string());
+        } else {
+            continue;
+        }
+        continuing = line.ends_with('\\');
+    }
+
+    commands
+}
+
+fn parse_index_filter(
+    raw: Option<&str>,
+) -> Result<Option<IndexFilter>, Box<dyn std::error::Error>> {
+    let Some(raw) = raw else {
+        return Ok(None);
+    };
+
+    if let Some((start, end)) = raw.split_once('-') {
+        let start = start.trim().parse::<usize>()?;
+        let end = end.trim().parse::<usize>()?;
+        if start > end {
+            return Err("range start must be <= end".into());
+        }
+        Ok(Some(IndexFilter::Range { start, end }))
+    } else {
+        let value = raw.trim().parse::<usize>()?;
+        Ok(Some(IndexFilter::Single(value)))
+    }
+}
+
+fn parse_line_range(raw: &str) -> Result<(usize, usize), Box<dyn std::error::Error>> {
+    let (start, end) = raw
+        .split_once('-')
+        .ok_or("line range must look like START-END")?;
+    let start = start.trim().parse::<usize>()?;
+    let end = end.trim().parse::<usize>()?;
+    if start == 0 || start > end {
+        return Err("line range must satisfy 1 <= start <= end".into());
+    }
+    Ok((start, end))
+}
+
+/// Parses a comma-separated list of 1-based lines and ranges such as `2,4-6`.
+fn parse_line_selection(raw: &str) -> Result<Vec<(usize, usize)>, Box<dyn std::error::Error>> {
+    raw.split(',')
+        .map(|part| {
+            if part.contains('-') {
+                parse_line_range(part)
+            } else {
+                let line = part.trim().parse::<usize>()?;
+                if line == 0 {
+                    return Err("line numbers start at 1".into());
+                }
+                Ok((line, line))
+            }
+        })
+        .collect()
+}
+
+/// True when `line` holds `marker` (`BEGIN`/`END`) followed by `name` as a
+/// whole word, whatever comment syntax surrounds it.
+fn is_region_marker(line: &str, marker: &str, name: &str) -> bool {
+    line.match_indices(marker).any(|(i, _)| {
+        let rest = line[i + marker.len()..].trim_start();
+        rest.strip_prefix(name).is_some_and(|after| {
+            !after.starts_with(|c: char| c.is_alphanumeric() || c == '_' || c == '-')
+        })
+    })
+}
+
+/// Narrows the block to the lines of region `name`, joining repeated regions.
+/// Returns false, leaving the block untouched, when the region is absent.
+fn extract_region(block: &mut CodeBlock, source: Option<&str>, name: &str) -> bool {
+    let mut kept = Vec::new();
+    let mut first_line = None;
+    let mut last_line = 0;
+    let mut inside = false;
+    for (offset, line) in block.code.lines().enumerate() {
+        if is_region_marker(line, "BEGIN", name) {
+            inside = true;
+        } else if is_region_marker(line, "END", name) {
+            inside = false;
+        } else if inside {
+            first_line.get_or_insert(offset);
+            last_line = offset;
+            kept.push(line);
+        }
+    }
+    let Some(first) = first_line else {
+        return false;
+    };
+    if let Some(start) = block.start_line {
+        block.start_line = Some(start + first);
+        block.end_line = Some(start + last_line);
+    }
+    block.code = kept.join("\n");
+    narrow_span(block, source, first, last_line + 1 - first);
+    true
+}
+
+/// Narrows `code` to the 1-based line range, clamping to the block and
+/// shifting the recorded source lines so numbering stays accurate.
+fn slice_lines(block: &mut CodeBlock, source: Option<&str>, start: usize, end: usize) {
+    let total = line_count(&block.code);
+    let clamped_end = end.min(total);
+    if start > total || end > total {
+        warn(
+            Some(&block.source),
+            block.start_line,
+            format!(
+                "block {} has {} lines; clamping --lines {}-{}",
+                block.index, total, start, end
+            ),
+        );
+    }
+    let clamped_start = start.min(clamped_end.max(1));
+
+    block.code = block
+        .code
+        .lines()
+        .skip(clamped_start - 1)
+        .take(clamped_end + 1 - clamped_start)
+        .collect::<Vec<_>>()
+        .join("\n");
+    if let Some(first) = block.start_line {
+        block.start_line = Some(first + clamped_start - 1);
+        block.end_line = Some(first + clamped_end.max(clamped_start) - 1);
+    }
+    let kept = (clamped_end + 1).saturating_sub(clamped_start);
+    narrow_span(block, source, clamped_start - 1, kept);
+}
+
+/// `--explain` bookkeeping: the active filters each parsed block passed, and
+/// the one that dropped it.
+struct FilterTrace {
+    entries: Vec<TraceEntry>,
+    /// Entry of each surviving block, keyed by its current index.
+    live: BTreeMap<usize, usize>,
+}
+
+struct TraceEntry {
+    label: String,
+    passed: Vec<&'static str>,
+    dropped_by: Option<&'static str>,
+}
+
+impl FilterTrace {
+    fn new(blocks: &[CodeBlock]) -> Self {
+        FilterTrace {
+            entries: blocks
+                .iter()
+                .map(|b| TraceEntry {
+                    label: format!("block {} {}", b.index, block_label(b)),
+                    passed: Vec::new(),
+                    dropped_by: None,
+                })
+                .collect(),
+            live: blocks
+                .iter()
+                .enumerate()
+                .map(|(entry, b)| (b.index, entry))
+                .collect(),
+        }
+    }
+
+    /// Credits `filter` to the blocks still in `blocks` and blames it for
+    /// every other block that was alive before it ran.
+    fn step(&mut self, filter: &'static str, blocks: &[CodeBlock]) {
+        let kept: BTreeSet<usize> = blocks.iter().map(|b| b.index).collect();
+        for (index, entry) in std::mem::take(&mut self.live) {
+            if kept.contains(&index) {
+                self.entries[entry].passed.push(filter);
+                self.live.insert(index, entry);
+            } else {
+                self.entries[entry].dropped_by = Some(filter);
+            }
+        }
+    }
+
+    /// Follows the survivors to their positions in `blocks`, ahead of a
+    /// renumbering from 0.
+    fn renumber(&mut self, blocks: &[CodeBlock]) {
+        self.live = blocks
+            .iter()
+            .enumerate()
+            .filter_map(|(position, b)| Some((position, *self.live.get(&b.index)?)))
+            .collect();
+    }
+
+    fn lines(&self) -> Vec<String> {
+        self.entries
+            .iter()
+            .map(|e| {
+                let passed = if e.passed.is_empty() {
+                    String::new()
+                } else {
+                    format!(" (passed {})", e.passed.join(", "))
+                };
+                match e.dropped_by {
+                    Some(filter) => format!("{}: dropped by {filter}{passed}", e.label),
+                    None => format!("{}: kept{passed}", e.label),
+                }
+            })
+            .collect()
+    }
+
+    fn report(&self) {
+        for line in self.lines() {
+            eprintln!("{line}");
+        }
+    }
+}
+
+fn explain_step(trace: &mut Option<FilterTrace>, filter: &'static str, blocks: &[CodeBlock]) {
+    if let Some(trace) = trace {
+        trace.step(filter, blocks);
+    }
+}
+
+fn apply_index_filter(blocks: Vec<CodeBlock>, filter: IndexFilter) -> Vec<CodeBlock> {
+    match filter {
+        IndexFilter::Single(n) => blocks.into_iter().filter(|b| b.index == n).collect(),
+        IndexFilter::Range { start, end } => blocks
+            .into_iter()
+            .filter(|b| b.index >= start && b.index <= end)
+            .collect(),
+    }
+}
+
+fn source_counts<'a>(names: &'a [String], blocks: &[CodeBlock]) -> Vec<(&'a str, usize)> {
+    names
+        .iter()
+        .map(|name| {
+            let count = blocks.iter().filter(|b| &b.source == name).count();
+            (name.as_str(), count)
+        })
+        .collect()
+}
+
+/// A column-aligned table of each block's global index, source, position
+/// among that source's blocks, language and line span.
+fn render_index_map(blocks: &[CodeBlock]) -> String {
+    let mut per_source: BTreeMap<&str, usize> = BTreeMap::new();
+    let mut rows = vec![[
+        "INDEX".to_string(),
+        "SOURCE".to_string(),
+        "SOURCE_INDEX".to_string(),
+        "LANG".to_string(),
+        "LINES".to_string(),
+    ]];
+    for block in blocks {
+        let position = per_source.entry(&block.source).or_default();
+        let lines = match (block.start_line, block.end_line) {
+            (Some(start), Some(end)) if start != end => format!("{start}-{end}"),
+            (Some(line), _) => line.to_string(),
+            _ => "-".to_string(),
+        };
+        rows.push([
+            block.index.to_string(),
+            block.source.clone(),
+            position.to_string(),
+            block.lang.clone().unwrap_or_else(|| "plain".to_string()),
+            lines,
+        ]);
+        *position += 1;
+    }
+
+    let mut widths = [0; 5];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let mut out = String::new();
+    for row in &rows {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:<width$}"))
+            .collect();
+        out.push_str(cells.join("  ").trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+fn lang_extension(lang: Option<&str>) -> &'static str {
+    let Some(lang) = lang else {
+        return "txt";
+    };
+
+    match lang.to_lowercase().as_str() {
+        "rust" | "rs" => "rs",
+        "python" | "py" => "py",
+        "javascript" | "js" => "js",
+        "typescript" | "ts" => "ts",
+        "bash" | "sh" | "shell" | "zsh" => "sh",
+        "c" => "c",
+        "cpp" | "c++" => "cpp",
+        "go" => "go",
+        "java" => "java",
+        "ruby" | "rb" => "rb",
+        "toml" => "toml",
+        "yaml" | "yml" => "yml",
+        "json" => "json",
+        "html" => "html",
+        "css" => "css",
+        "sql" => "sql",
+        "markdown" | "md" => "md",
+        _ => "txt",
+    }
+}
+
+fn expand_template(template: &str, block: &CodeBlock) -> PathBuf {
+    let source = Path::new(&block.source);
+    let stem = source
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| block.source.clone());
+    let dir = source
+        .parent()
+        .map(|p| p.to_string_lossy().into_owned())
+        .filter(|p| !p.is_empty())
+        .unwrap_or_else(|| ".".to_string());
+
+    let expanded = template
+        .replace("{source_dir}", &dir)
+        .replace("{source_stem}", &stem)
+        .replace("{index}", &block.index.to_string())
+        .replace(
+            "{lang}",
+            &slug(block.lang.as_deref().unwrap_or_default(), "plain"),
+        )
+        .replace("{ext}", lang_extension(block.lang.as_deref()))
+        .replace("{section}", &section_slug(block.section.as_deref()));
+    PathBuf::from(expanded)
+}
+
+/// A path-safe name for a section, or `preamble` for blocks before the
+/// first heading.
+fn section_slug(section: Option<&str>) -> String {
+    slug(section.unwrap_or_default(), "preamble")
+}
+
+/// Lowercase alphanumerics of `text` joined by `-`; `fallback` when none.
+fn slug(text: &str, fallback: &str) -> String {
+    let slug = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join("-");
+    if slug.is_empty() {
+        fallback.to_string()
+    } else {
+        slug
+    }
+}
+
+fn plan_outputs<'a>(
+    blocks: &'a [CodeBlock],
+    template: &str,
+    force: bool,
+) -> Result<Vec<(PathBuf, &'a CodeBlock)>, Box<dyn std::error::Error>> {
+    let mut seen = BTreeMap::new();
+    let mut outputs = Vec::new();
+
+    for block in blocks {
+        let path = expand_template(template, block);
+        if path.is_absolute()
+            || path
+                .components()
+                .any(|c| matches!(c, std::path::Component::ParentDir))
+        {
+            return Err(format!(
+                "block {} maps to {}, outside the output directory; templates must expand to relative paths without `..`",
+                block.index,
+                path.display()
+            )
+            .into());
+        }
+        if let Some(previous) = seen.insert(path.clone(), block.index)
+            && !force
+        {
+            return Err(format!(
+                "blocks {previous} and {} both map to {}; use --force to overwrite",
+                block.index,
+                path.display()
+            )
+            .into());
+        }
+        outputs.push((path, block));
+    }
+
+    Ok(outputs)
+}
+
+/// Blocks in document order separated by blank lines, framed by the optional
+/// header and footer.
+fn merge_blocks(blocks: &[CodeBlock], prepend: Option<&str>, append: Option<&str>) -> String {
+    let codes: Vec<&str> = blocks.iter().map(|b| b.code.as_str()).collect();
+    let body = codes.join("\n\n");
+    let mut parts: Vec<&str> = prepend.into_iter().collect();
+    parts.push(&body);
+    parts.extend(append);
+    format!("{}\n", parts.join("\n"))
+}
+
+fn write_outputs(
+    outputs: &[(PathBuf, &CodeBlock)],
+    dry_run: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for (path, block) in outputs {
+        let data = format!("{}\n", block.code);
+        if dry_run {
+            report_write(path, data.len())?;
+            continue;
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, data)?;
+    }
+    Ok(())
+}
+
+/// The `--dry-run` stand-in for writing `len` bytes to `path`.
+fn report_write(path: &Path, len: usize) -> io::Result<()> {
+    writeln!(Output, "would write {} ({len} bytes)", path.display())
+}
+
+/// Entry names for `--tar` without an `--output-template`.
+const DEFAULT_TAR_TEMPLATE: &str = "{index}.{ext}";
+
+/// Streams each planned output as an archive entry holding the same bytes
+/// `write_outputs` would write, returning the writer after the end marker.
+fn write_tar<W: Write>(
+    out: W,
+    outputs: &[(PathBuf, &CodeBlock)],
+    mtime: u64,
+) -> Result<W, Box<dyn std::error::Error>> {
+    let mut archive = tar::Builder::new(out);
+    for (path, block) in outputs {
+        let data = format!("{}\n", block.code);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(mtime);
+        archive
+            .append_data(&mut header, path, data.as_bytes())
+            .map_err(|e| format!("cannot archive {}: {e}", path.display()))?;
+    }
+    Ok(archive.into_inner()?)
+}
+
+/// One `--source-map` entry tying an emitted block back to the docs.
+#[derive(Debug, Serialize)]
+struct SourceMapEntry<'a> {
+    index: usize,
+    /// File the block was written to under --output-template.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output: Option<&'a Path>,
+    source: &'a str,
+    lang: Option<&'a str>,
+    start_line: Option<usize>,
+    end_line: Option<usize>,
+    byte_start: Option<usize>,
+    byte_end: Option<usize>,
+}
+
+impl<'a> SourceMapEntry<'a> {
+    fn new(block: &'a CodeBlock, output: Option<&'a Path>) -> Self {
+        SourceMapEntry {
+            index: block.index,
+            output,
+            source: &block.source,
+            lang: block.lang.as_deref(),
+            start_line: block.start_line,
+            end_line: block.end_line,
+            byte_start: block.byte_start,
+            byte_end: block.byte_end,
+        }
+    }
+}
+
+fn write_source_map(
+    path: &Path,
+    entries: Vec<SourceMapEntry>,
+    dry_run: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut json = serde_json::to_vec_pretty(&entries)?;
+    json.push(b'\n');
+    if dry_run {
+        report_write(path, json.len())?;
+    } else {
+        fs::write(path, json)?;
+    }
+    Ok(())
+}
+
+fn diff_pairing_key(blocks: &[CodeBlock], key: DiffKey) -> Vec<(String, &CodeBlock)> {
+    let mut seen: BTreeMap<String, usize> = BTreeMap::new();
+    blocks
+        .iter()
+        .enumerate()
+        .map(|(position, block)| match key {
+            DiffKey::Index => (format!("#{position}"), block),
+            DiffKey::LangPosition => {
+                let lang = block.lang.as_deref().unwrap_or("plain").to_lowercase();
+                let nth = seen.entry(lang.clone()).or_default();
+                *nth += 1;
+                (format!("{lang}#{}", *nth - 1), block)
+            }
+        })
+        .collect()
+}
+
+fn block_label(block: &CodeBlock) -> String {
+    format!(
+        "{}:{} ({})",
+        block.source,
+        block.start_line.unwrap_or(1),
+        block.lang.as_deref().unwrap_or("plain")
+    )
+}
+
+/// Renders a unified diff of paired block bodies plus notes for unpaired
+/// blocks. The result is empty when both sides match.
+fn diff_blocks(ours: &[CodeBlock], theirs: &[CodeBlock], key: DiffKey) -> String {
+    let ours = diff_pairing_key(ours, key);
+    let mut theirs: BTreeMap<String, &CodeBlock> =
+        diff_pairing_key(theirs, key).into_iter().collect();
+    let mut report = String::new();
+
+    for (id, old) in ours {
+        let Some(new) = theirs.remove(&id) else {
+            report.push_str(&format!("block {id} removed: {}\n", block_label(old)));
+            continue;
+        };
+        if old.code == new.code {
+            continue;
+        }
+
+        let old_text = format!("{}\n", old.code);
+        let new_text = format!("{}\n", new.code);
+        let diff = similar::TextDiff::from_lines(&old_text, &new_text);
+        report.push_str(
+            &diff
+                .unified_diff()
+                .header(
+                    &format!("{id} {}", block_label(old)),
+                    &format!("{id} {}", block_label(new)),
+                )
+                .to_string(),
+        );
+    }
+
+    let mut added: Vec<_> = theirs.into_iter().collect();
+    added.sort_by_key(|(_, b)| b.index);
+    for (id, new) in added {
+        report.push_str(&format!("block {id} added: {}\n", block_label(new)));
+    }
+
+    report
+}
+
+/// Groups blocks by canonical language; blocks without one go under `plain`.
+fn collate<'a>(
+    blocks: &'a [CodeBlock],
+    aliases: &BTreeMap<String, String>,
+) -> BTreeMap<String, Vec<&'a CodeBlock>> {
+    let mut groups: BTreeMap<String, Vec<&CodeBlock>> = BTreeMap::new();
+    for block in blocks {
+        let lang = block
+            .lang
+            .as_deref()
+            .map(|l| canonical_lang(l, aliases))
+            .unwrap_or_else(|| "plain".to_string());
+        groups.entry(lang).or_default().push(block);
+    }
+    groups
+}
+
+fn render_collated(
+    groups: &BTreeMap<String, Vec<&CodeBlock>>,
+    render: &RenderOptions,
+    separator: &str,
+) -> String {
+    groups
+        .iter()
+        .map(|(lang, group)| {
+            let body = join_rendered(group.iter().copied(), render, separator, None);
+            format!("## {lang}\n\n{body}")
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Code size of a group of blocks: chars are Unicode scalar values, bytes are
+/// UTF-8 length.
+#[derive(Debug, Default, Serialize)]
+struct Volume {
+    lines: usize,
+    chars: usize,
+    bytes: usize,
+}
+
+impl Volume {
+    fn of<'a>(blocks: impl IntoIterator<Item = &'a CodeBlock>) -> Self {
+        let mut volume = Volume::default();
+        for block in blocks {
+            volume.lines += line_count(&block.code);
+            volume.chars += block.code.chars().count();
+            volume.bytes += block.code.len();
+        }
+        volume
+    }
+}
+
+fn render_stats(blocks: &[CodeBlock], aliases: &BTreeMap<String, String>) -> String {
+    let total = Volume::of(blocks);
+    let mut out = format!(
+        "blocks: {}\nlines: {}\nchars: {}\nbytes: {}\n",
+        blocks.len(),
+        total.lines,
+        total.chars,
+        total.bytes
+    );
+    for (lang, group) in collate(blocks, aliases) {
+        let volume = Volume::of(group.iter().copied());
+        out.push_str(&format!(
+            "{lang}: {} blocks ({} lines, {} chars, {} bytes)\n",
+            group.len(),
+            volume.lines,
+            volume.chars,
+            volume.bytes
+        ));
+    }
+    out
+}
+
+/// The `--summary-json` object; every field is present even with no blocks.
+#[derive(Debug, Serialize)]
+struct Summary<'a> {
+    blocks: usize,
+    lines: usize,
+    chars: usize,
+    bytes: usize,
+    languages: BTreeMap<String, usize>,
+    /// Per-language lines, chars and bytes.
+    language_volume: BTreeMap<String, Volume>,
+    kinds: BTreeMap<&'static str, usize>,
+    sources: &'a [String],
+}
+
+impl<'a> Summary<'a> {
+    fn new(
+        blocks: &[CodeBlock],
+        sources: &'a [String],
+        aliases: &BTreeMap<String, String>,
+    ) -> Self {
+        let count = |kind| blocks.iter().filter(|b| b.kind == kind).count();
+        let total = Volume::of(blocks);
+        let collated = collate(blocks, aliases);
+        Summary {
+            blocks: blocks.len(),
+            lines: total.lines,
+            chars: total.chars,
+            bytes: total.bytes,
+            languages: collated
+                .iter()
+                .map(|(lang, group)| (lang.clone(), group.len()))
+                .collect(),
+            language_volume: collated
+                .into_iter()
+                .map(|(lang, group)| (lang, Volume::of(group)))
+                .collect(),
+            kinds: BTreeMap::from([
+                ("fenced", count(BlockKind::Fenced)),
+                ("inline", count(BlockKind::Inline)),
+            ]),
+            sources,
+        }
+    }
+}
+
+/// One language of `--lang --json`; `lang` is null for unlabeled blocks.
+#[derive(Debug, Default, Serialize)]
+struct LangEntry<'a> {
+    lang: Option<&'a str>,
+    count: usize,
+    fenced: usize,
+    inline: usize,
+    /// Sources containing the language, in reading order.
+    sources: Vec<&'a str>,
+}
+
+/// Per-language counts, named languages alphabetically, unlabeled last.
+fn language_inventory(blocks: &[CodeBlock]) -> Vec<LangEntry<'_>> {
+    let mut named: BTreeMap<&str, LangEntry> = BTreeMap::new();
+    let mut unlabeled = LangEntry::default();
+    for block in blocks {
+        let entry = match block.lang.as_deref() {
+            Some(lang) => named.entry(lang).or_insert_with(|| LangEntry {
+                lang: Some(lang),
+                ..Default::default()
+            }),
+            None => &mut unlabeled,
+        };
+        entry.count += 1;
+        match block.kind {
+            BlockKind::Fenced => entry.fenced += 1,
+            BlockKind::Inline => entry.inline += 1,
+        }
+        if !entry.sources.contains(&block.source.as_str()) {
+            entry.sources.push(&block.source);
+        }
+    }
+    let mut inventory: Vec<LangEntry> = named.into_values().collect();
+    if unlabeled.count > 0 {
+        inventory.push(unlabeled);
+    }
+    inventory
+}
+
+fn languages(blocks: &[CodeBlock]) -> BTreeSet<&str> {
+    blocks.iter().filter_map(|b| b.lang.as_deref()).collect()
+}
+
+/// Optional fields included in JSON output.
+#[derive(Clone, Copy, Debug, Default)]
+struct JsonFields<'a> {
+    line_numbers: bool,
+    headings: bool,
+    /// With `--annotate`, the aliases used to derive `canonical_lang`.
+    annotate: Option<&'a BTreeMap<String, String>>,
+    block_ids: bool,
+}
+
+/// A diagnostic. Printed to stderr unless `--ndjson-warnings` collects it
+/// for the output stream.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Warning {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<usize>,
+    message: String,
+}
+
+/// Warnings gathered for `--ndjson-warnings`; `None` while they go to stderr.
+static WARNINGS: Mutex<Option<Vec<Warning>>> = Mutex::new(None);
+
+fn collect_warnings() {
+    *WARNINGS.lock().unwrap_or_else(PoisonError::into_inner) = Some(Vec::new());
+}
+
+fn take_warnings() -> Vec<Warning> {
+    WARNINGS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .take()
+        .unwrap_or_default()
+}
+
+thread_local! {
+    /// Warnings held back by `capture_warnings` on this thread.
+    static CAPTURED: RefCell<Option<Vec<Warning>>> = const { RefCell::new(None) };
+}
+
+/// Runs `f`, returning the warnings it raised instead of reporting them.
+fn capture_warnings<T>(f: impl FnOnce() -> T) -> (T, Vec<Warning>) {
+    let outer = CAPTURED.replace(Some(Vec::new()));
+    let value = f();
+    let captured = CAPTURED.replace(outer).unwrap_or_default();
+    (value, captured)
+}
+
+fn warn(source: Option<&str>, line: Option<usize>, message: String) {
+    let warning = Warning {
+        source: source.map(str::to_string),
+        line,
+        message,
+    };
+    let warning = CAPTURED.with_borrow_mut(|captured| match captured {
+        Some(captured) => {
+            captured.push(warning);
+            None
+        }
+        None => Some(warning),
+    });
+    let Some(warning) = warning else {
+        return;
+    };
+    match WARNINGS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .as_mut()
+    {
+        Some(warnings) => warnings.push(warning),
+        None => eprintln!("warning: {}", warning.message),
+    }
+}
+
+/// One line of `--ndjson-warnings` output.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Record {
+    Warning(Warning),
+    Block(Box<JsonBlock>),
+}
+
+/// Writes blocks and warnings as tagged NDJSON records ordered by source,
+/// then line; a warning comes before a block starting on the same line and
+/// source-less warnings come first. Unterminated fences add a warning each.
+fn write_tagged_ndjson<W: Write>(
+    mut out: W,
+    blocks: &[CodeBlock],
+    fields: JsonFields,
+    sources: &[String],
+    warnings: Vec<Warning>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let rank = |source: Option<&str>| {
+        source.map_or(0, |s| {
+            sources.iter().position(|n| n == s).map_or(0, |i| i + 1)
+        })
+    };
+    let mut records: Vec<(usize, usize, Record)> = warnings
+        .into_iter()
+        .map(|w| {
+            (
+                rank(w.source.as_deref()),
+                w.line.unwrap_or(0),
+                Record::Warning(w),
+            )
+        })
+        .collect();
+    for block in blocks {
+        let line = block.fence_start_line.or(block.start_line).unwrap_or(0);
+        if !block.closed {
+            let warning = Warning {
+                source: Some(block.source.clone()),
+                line: Some(line),
+                message: "unterminated fence runs to the end of the input".to_string(),
+            };
+            records.push((rank(Some(&block.source)), line, Record::Warning(warning)));
+        }
+        records.push((
+            rank(Some(&block.source)),
+            line,
+            Record::Block(Box::new(JsonBlock::new(block, fields))),
+        ));
+    }
+    // Stable, so equal keys keep warnings ahead of their block.
+    records.sort_by_key(|(rank, line, record)| (*rank, *line, matches!(record, Record::Block(_))));
+    for (_, _, record) in &records {
+        serde_json::to_writer(&mut out, record)?;
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+fn emit_json<W: Write>(
+    mut out: W,
+    blocks: &[CodeBlock],
+    fields: JsonFields,
+    style: JsonStyle,
+    compact: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let payload: Vec<JsonBlock> = blocks.iter().map(|b| JsonBlock::new(b, fields)).collect();
+
+    match style {
+        JsonStyle::Array => write_json_to(out, &payload, compact),
+        JsonStyle::Ndjson => {
+            for block in &payload {
+                write_json_to(&mut out, block, true)?;
+            }
+            Ok(())
+        }
+        JsonStyle::Objects => {
+            for (i, block) in payload.iter().enumerate() {
+                if i > 0 {
+                    writeln!(out)?;
+                }
+                write_json_to(&mut out, block, false)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+fn write_json<T: Serialize + ?Sized>(
+    value: &T,
+    compact: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    write_json_to(Output, value, compact)
+}
+
+fn write_json_to<W: Write, T: Serialize + ?Sized>(
+    mut out: W,
+    value: &T,
+    compact: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if compact {
+        serde_json::to_writer(&mut out, value)?;
+    } else {
+        serde_json::to_writer_pretty(&mut out, value)?;
+    }
+    writeln!(out)?;
+    Ok(())
+}
+
+/// Serializes blocks one at a time, so no JSON payload is held in memory.
+fn write_json_lines<W: Write>(
+    mut out: W,
+    blocks: &[CodeBlock],
+    fields: JsonFields,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for block in blocks {
+        serde_json::to_writer(&mut out, &JsonBlock::new(block, fields))?;
+        out.write_all(b"\n")?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Top-level object of `--json-envelope` output.
+#[derive(Debug, Serialize)]
+struct Envelope {
+    version: &'static str,
+    /// RFC 3339 time of the run, in UTC; omitted with `--reproducible`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    generated_at: Option<String>,
+    count: usize,
+    blocks: Vec<JsonBlock>,
+}
+
+impl Envelope {
+    fn new(blocks: &[CodeBlock], fields: JsonFields, timestamp: bool) -> Self {
+        Envelope {
+            version: env!("CARGO_PKG_VERSION"),
+            generated_at: timestamp
+                .then(|| humantime::format_rfc3339_seconds(SystemTime::now()).to_string()),
+            count: blocks.len(),
+            blocks: blocks.iter().map(|b| JsonBlock::new(b, fields)).collect(),
+        }
+    }
+}
+
+/// One extracted code block as emitted by `--json`.
+#[derive(Debug, Serialize, JsonSchema)]
+struct JsonBlock {
+    /// Position of the block across all inputs, starting at 0.
+    index: usize,
+    /// File name the block came from, or `stdin`.
+    source: String,
+    kind: BlockKind,
+    /// Info-string language of a fenced block.
+    lang: Option<String>,
+    /// Info-string attributes (`key=value`, `.class` under `class`, `#id` under `id`).
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    attrs: BTreeMap<String, String>,
+    /// Columns the opening fence was indented; 0 for inline spans.
+    indent: usize,
+    /// First content line (present with `--line-numbers`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    start_line: Option<usize>,
+    /// Last content line (present with `--line-numbers`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    end_line: Option<usize>,
+    code: String,
+    /// Blank lines trimmed from the end of `code`.
+    trailing_newlines: usize,
+    /// Hex SHA-256 of the block's code as written in the source.
+    hash: String,
+    /// Stable ID from the source and first code line; survives moving the
+    /// block within its file (present with `--block-ids`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    block_id: Option<String>,
+    /// `code`, `diagram`, `math`, or a category from the config.
+    category: String,
+    /// Commands of a shell session block (present with `--split-prompts`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    commands: Option<Vec<String>>,
+    /// Enclosing headings, outermost first (present with `--context-headings`).
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    heading_path: Vec<String>,
+    /// Line of the opening fence (fenced blocks, with `--line-numbers`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fence_start_line: Option<usize>,
+    /// Line of the closing fence; absent when the fence is unterminated.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fence_end_line: Option<usize>,
+    /// Whether the block's fence was closed; inline spans always are.
+    closed: bool,
+    /// Table row of a `--tables` span; 0 is the header row.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    row: Option<usize>,
+    /// Table column of a `--tables` span, from 0.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    col: Option<usize>,
+    /// Source lines before the block (present with `--context` or `--before`).
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    context_before: Vec<String>,
+    /// Source lines after the block (present with `--context` or `--after`).
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    context_after: Vec<String>,
+    /// Derived fields (present with `--annotate`).
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    annotations: Option<Annotations>,
+}
+
+/// Values derived from a block's `code` and `lang` for `--annotate`.
+#[derive(Debug, Serialize, JsonSchema)]
+struct Annotations {
+    lines: usize,
+    /// Unicode scalar values in `code`.
+    chars: usize,
+    /// UTF-8 length of `code`.
+    bytes: usize,
+    /// Whether `code` is empty or only whitespace.
+    is_empty: bool,
+    /// Lowercased `lang` with config aliases applied.
+    canonical_lang: Option<String>,
+}
+
+impl Annotations {
+    fn new(b: &CodeBlock, aliases: &BTreeMap<String, String>) -> Self {
+        Annotations {
+            lines: line_count(&b.code),
+            chars: b.code.chars().count(),
+            bytes: b.code.len(),
+            is_empty: b.code.trim().is_empty(),
+            canonical_lang: b.lang.as_deref().map(|l| canonical_lang(l, aliases)),
+        }
+    }
+}
+
+impl JsonBlock {
+    fn new(b: &CodeBlock, fields: JsonFields) -> Self {
+        let include_line_numbers = fields.line_numbers;
+        JsonBlock {
+            index: b.index,
+            source: b.source.clone(),
+            kind: b.kind,
+            lang: b.lang.clone(),
+            attrs: b.attrs.clone(),
+            indent: b.indent,
+            start_line: include_line_numbers.then_some(b.start_line).flatten(),
+            end_line: include_line_numbers.then_some(b.end_line).flatten(),
+            code: b.code.clone(),
+            trailing_newlines: b.trailing_newlines,
+            hash: b.hash.clone(),
+            block_id: fields.block_ids.then(|| b.block_id.clone()),
+            category: b.category.clone(),
+            commands: b.commands.clone(),
+            heading_path: if fields.headings {
+                b.heading_path.clone()
+            } else {
+                Vec::new()
+            },
+            fence_start_line: include_line_numbers.then_some(b.fence_start_line).flatten(),
+            fence_end_line: include_line_numbers.then_some(b.fence_end_line).flatten(),
+            closed: b.closed,
+            row: b.row,
+            col: b.col,
+            context_before: b.context_before.clone(),
+            context_after: b.context_after.clone(),
+            annotations: fields.annotate.map(|aliases| Annotations::new(b, aliases)),
+        }
+    }
+}
+
+/// Truncates a display line to `width` columns, ending it with an ellipsis.
+fn fit_width(line: &str, width: Option<usize>) -> String {
+    match width {
+        Some(width) => console::truncate_str(line, width, "…").into_owned(),
+        None => line.to_string(),
+    }
+}
+
+fn list_line(block: &CodeBlock, include_line_numbers: bool, include_headings: bool) -> String {
+    let lang = block.lang.clone().unwrap_or_else(|| "plain".to_string());
+    let lines = line_count(&block.code);
+    let location = if include_line_numbers {
+        match (block.start_line, block.end_line) {
+            (Some(start), Some(end)) if start != end => {
+                format!("{}:{}-{}", block.source, start, end)
+            }
+            (Some(line), _) => format!("{}:{}", block.source, line),
+            _ => block.source.clone(),
+        }
+    } else {
+        block.source.clone()
+    };
+
+    let line = format!("{}: {} ({} lines) [{}]", block.index, lang, lines, location);
+    if include_headings && !block.heading_path.is_empty() {
+        format!("{line} {}", block.heading_path.join(" > "))
+    } else {
+        line
+    }
+}
+
+/// Expands a `--list-format` template. Line placeholders expand to an empty
+/// string for blocks without line information.
+fn format_list_line(template: &str, block: &CodeBlock) -> String {
+    let line = |n: Option<usize>| n.map(|n| n.to_string()).unwrap_or_default();
+    template
+        .replace("{index}", &block.index.to_string())
+        .replace("{source}", &block.source)
+        .replace("{lang}", block.lang.as_deref().unwrap_or("plain"))
+        .replace("{kind}", block.kind.name())
+        .replace("{lines}", &line_count(&block.code).to_string())
+        .replace("{start_line}", &line(block.start_line))
+        .replace("{end_line}", &line(block.end_line))
+        .replace("{heading}", &block.heading_path.join(" > "))
+        .replace("{hash}", &block.hash)
+}
+
+fn render_peek(
+    block: &CodeBlock,
+    n: usize,
+    include_line_numbers: bool,
+    include_headings: bool,
+) -> String {
+    let lines: Vec<&str> = block.code.lines().collect();
+    let mut out = vec![list_line(block, include_line_numbers, include_headings)];
+
+    if lines.len() > n * 2 {
+        out.extend(lines[..n].iter().map(|l| format!("  {l}")));
+        out.push(format!("  ... ({} more lines)", lines.len() - n * 2));
+        out.extend(lines[lines.len() - n..].iter().map(|l| format!("  {l}")));
+    } else {
+        out.extend(lines.iter().map(|l| format!("  {l}")));
+    }
+
+    out.join("\n")
+}
+
+#[derive(Debug, Clone, Default)]
+struct RenderOptions {
+    fenced: bool,
+    fence_char: FenceChar,
+    show_fences: bool,
+    preserve_trailing: bool,
+    source_comment: bool,
+    line_numbers: bool,
+    number_start: Option<usize>,
+    gutter_width: Option<usize>,
+    /// Block-relative line ranges from `--number-lines`; `None` numbers every line.
+    number_lines: Option<Vec<(usize, usize)>>,
+    /// Width for `--file-separator` banners; code is never truncated.
+    preview_width: Option<usize>,
+    /// Emit a `=== Section ===` marker where `--split-on-heading` sections begin.
+    sections: bool,
+    /// Render nothing, not even a separator, for blocks with a blank body.
+    skip_empty: bool,
+    /// Spaces added before each non-blank rendered line, fences included.
+    indent: usize,
+}
+
+impl RenderOptions {
+    /// Whether `--skip-empty-output` leaves `block` out entirely.
+    fn skips(&self, block: &CodeBlock) -> bool {
+        self.skip_empty && is_blank_block(block)
+    }
+}
+
+const HIGHLIGHT_START: &str = "\x1b[1;31m";
+const HIGHLIGHT_END: &str = "\x1b[0m";
+
+/// Wraps each match of `pattern` in ANSI highlighting. Lines are matched one
+/// at a time so a highlight never spans a line break.
+fn highlight_matches(code: &str, pattern: &Regex) -> String {
+    code.split('\n')
+        .map(|line| {
+            let mut out = String::new();
+            let mut last = 0;
+            for m in pattern.find_iter(line).filter(|m| !m.is_empty()) {
+                out.push_str(&line[last..m.start()]);
+                out.push_str(HIGHLIGHT_START);
+                out.push_str(m.as_str());
+                out.push_str(HIGHLIGHT_END);
+                last = m.end();
+            }
+            out.push_str(&line[last..]);
+            out
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn escape_code(code: &str, target: EscapeTarget) -> String {
+    match target {
+        EscapeTarget::Shell => format!("'{}'", code.replace('\'', "'\\''")),
+        EscapeTarget::Json => serde_json::Value::from(code).to_string(),
+        EscapeTarget::C => {
+            let mut out = String::from("\"");
+            for c in code.chars() {
+                match c {
+                    '\\' => out.push_str("\\\\"),
+                    '"' => out.push_str("\\\""),
+                    '\n' => out.push_str("\\n"),
+                    '\t' => out.push_str("\\t"),
+                    '\r' => out.push_str("\\r"),
+                    // Octal escapes stop after three digits, unlike `\x`.
+                    c if c.is_ascii_control() => out.push_str(&format!("\\{:03o}", c as u32)),
+                    c => out.push(c),
+                }
+            }
+            out.push('"');
+            out
+        }
+    }
+}
+
+/// Raw output of `blocks`. `final_newline` forces whether a newline is added
+/// after the last block; `None` adds one unless the separator ends in one.
+fn raw_output(
+    blocks: &[CodeBlock],
+    render: &RenderOptions,
+    separator: &str,
+    file_separator: Option<&str>,
+    final_newline: Option<bool>,
+) -> String {
+    let mut out = join_rendered(blocks, render, separator, file_separator);
+    let add = final_newline.unwrap_or(!separator.ends_with('\n'));
+    if add && blocks.iter().any(|b| !render.skips(b)) {
+        out.push('\n');
+    }
+    out
+}
+
+fn join_rendered<'a>(
+    blocks: impl IntoIterator<Item = &'a CodeBlock>,
+    render: &RenderOptions,
+    separator: &str,
+    file_separator: Option<&str>,
+) -> String {
+    let mut output = String::new();
+    let mut previous: Option<&CodeBlock> = None;
+
+    for block in blocks.into_iter().filter(|b| !render.skips(b)) {
+        if let Some(prev) = previous {
+            output.push_str(separator);
+            if let Some(banner) = file_separator
+                && prev.source != block.source
+            {
+                let banner = banner.replace("{source}", &block.source);
+                output.push_str(&fit_width(&banner, render.preview_width));
+                output.push('\n');
+            }
+        }
+        if render.sections
+            && previous.is_none_or(|p| p.source != block.source || p.section != block.section)
+        {
+            let name = block.section.as_deref().unwrap_or("(preamble)");
+            output.push_str(&fit_width(&format!("=== {name} ==="), render.preview_width));
+            output.push('\n');
+        }
+        output.push_str(&render_block(block, render));
+        previous = Some(block);
+    }
+
+    output
+}
+
+fn render_block(block: &CodeBlock, render: &RenderOptions) -> String {
+    let mut content = if render.line_numbers {
+        let start = render
+            .number_start
+            .unwrap_or_else(|| block.start_line.unwrap_or(1));
+        add_line_numbers(
+            &block.code,
+            start,
+            render.gutter_width,
+            render.number_lines.as_deref(),
+        )
+    } else {
+        block.code.clone()
+    };
+
+    if render.fenced {
+        let fence = fence_for(&content, render.fence_char.as_char());
+        let lang = block.lang.clone().unwrap_or_default();
+        if render.preserve_trailing {
+            content.push_str(&"\n".repeat(block.trailing_newlines));
+        }
+        if render.source_comment {
+            content = format!("{}\n{content}", source_comment(block));
+        }
+        content = format!("{fence}{lang}\n{content}\n{fence}");
+    } else if render.show_fences
+        && let Some(open) = &block.fence_open
+    {
+        // Restore the margin stripped during parsing so the excerpt lines up.
+        if block.indent > 0 {
+            let margin = " ".repeat(block.indent);
+            content = content
+                .lines()
+                .map(|l| {
+                    if l.is_empty() {
+                        String::new()
+                    } else {
+                        format!("{margin}{l}")
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+        }
+        content = match &block.fence_close {
+            Some(close) => format!("{open}\n{content}\n{close}"),
+            None => format!("{open}\n{content}"),
+        };
+    }
+
+    if !block.context_before.is_empty() {
+        content = format!("{}\n{content}", block.context_before.join("\n"));
+    }
+    if !block.context_after.is_empty() {
+        content = format!("{content}\n{}", block.context_after.join("\n"));
+    }
+    if render.indent > 0 {
+        content = indent_lines(&content, render.indent);
+    }
+    content
+}
+
+fn indent_lines(content: &str, width: usize) -> String {
+    let margin = " ".repeat(width);
+    content
+        .split('\n')
+        .map(|line| {
+            if line.trim().is_empty() {
+                line.to_string()
+            } else {
+                format!("{margin}{line}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct CommentSyntax {
+    line: Option<&'static str>,
+    block: Option<(&'static str, &'static str)>,
+}
+
+fn comment_syntax(lang: Option<&str>) -> Option<CommentSyntax> {
+    let c_like = CommentSyntax {
+        line: Some("//"),
+        block: Some(("/*", "*/")),
+    };
+    let hash = CommentSyntax {
+        line: Some("#"),
+        block: None,
+    };
+
+    let syntax = match lang?.to_lowercase().as_str() {
+        "rust" | "rs" | "c" | "cpp" | "c++" | "java" | "javascript" | "js" | "typescript"
+        | "ts" | "go" | "swift" | "kotlin" | "scala" | "csharp" | "cs" | "php" => c_like,
+        "python" | "py" | "bash" | "sh" | "shell" | "zsh" | "ruby" | "rb" | "perl" | "r"
+        | "yaml" | "yml" | "toml" | "dockerfile" | "make" | "makefile" => hash,
+        "sql" | "lua" | "haskell" | "hs" => CommentSyntax {
+            line: Some("--"),
+            block: None,
+        },
+        "lisp" | "clojure" | "scheme" | "ini" | "asm" => CommentSyntax {
+            line: Some(";"),
+            block: None,
+        },
+        "html" | "xml" | "svg" | "markdown" | "md" => CommentSyntax {
+            line: None,
+            block: Some(("<!--", "-->")),
+        },
+        "css" => CommentSyntax {
+            line: None,
+            block: Some(("/*", "*/")),
+        },
+        _ => return None,
+    };
+    Some(syntax)
+}
+
+/// Removes comments using `syntax`. A line marker only counts at the start
+/// of a line or after whitespace, and markers inside double-quoted strings
+/// are ignored; lines left empty by the removal are dropped.
+fn strip_comments(code: &str, syntax: CommentSyntax) -> String {
+    let mut out: Vec<String> = Vec::new();
+    let mut in_block = false;
+
+    for line in code.lines() {
+        let mut kept = String::new();
+        let mut in_string = false;
+        let mut rest = line;
+
+        while let Some(c) = rest.chars().next() {
+            if in_block {
+                match syntax
+                    .block
+                    .and_then(|(_, close)| rest.find(close).map(|i| i + close.len()))
+                {
+                    Some(end) => {
+                        in_block = false;
+                        rest = &rest[end..];
+                    }
+                    None => rest = "",
+                }
+                continue;
+            }
+            if in_string {
+                if c == '\\' {
+                    let len = rest.chars().take(2).map(char::len_utf8).sum();
+                    kept.push_str(&rest[..len]);
+                    rest = &rest[len..];
+                    continue;
+                }
+                in_string = c != '"';
+            } else if c == '"' {
+                in_string = true;
+            } else if let Some((open, _)) = syntax.block
+                && rest.starts_with(open)
+            {
+                in_block = true;
+                rest = &rest[open.len()..];
+                continue;
+            } else if let Some(marker) = syntax.line
+                && rest.starts_with(marker)
+                && kept.chars().last().is_none_or(char::is_whitespace)
+            {
+                break;
+            }
+            kept.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+
+        if kept.len() == line.len() {
+            out.push(kept);
+        } else if !kept.trim().is_empty() {
+            out.push(kept.trim_end().to_string());
+        }
+    }
+
+    out.join("\n")
+}
+
+/// A `source:line` comment in the block's language, falling back to `#`.
+fn source_comment(block: &CodeBlock) -> String {
+    let location = format!("{}:{}", block.source, block.start_line.unwrap_or(1));
+    match comment_syntax(block.lang.as_deref()) {
+        Some(CommentSyntax {
+            line: Some(prefix), ..
+        }) => format!("{prefix} {location}"),
+        Some(CommentSyntax {
+            block: Some((open, close)),
+            ..
+        }) => format!("{open} {location} {close}"),
+        _ => format!("# {location}"),
+    }
+}
+
+/// Picks a fence long enough that no run of `fence_char` inside `content`
+/// can close it early.
+fn fence_for(content: &str, fence_char: char) -> String {
+    let mut longest = 0;
+    let mut run = 0;
+    for c in content.chars() {
+        if c == fence_char {
+            run += 1;
+            longest = longest.max(run);
+        } else {
+            run = 0;
+        }
+    }
+
+    fence_char.to_string().repeat((longest + 1).max(3))
+}
+
+fn render_markdown(blocks: &[CodeBlock], render: &RenderOptions) -> String {
+    // A document fences every block, whatever the raw output would do.
+    let render = RenderOptions {
+        fenced: true,
+        ..render.clone()
+    };
+    let mut sections = Vec::new();
+    let mut previous_heading: Option<&str> = None;
+
+    for block in blocks.iter().filter(|b| !render.skips(b)) {
+        if let Some(heading) = block.heading()
+            && previous_heading != Some(heading)
+        {
+            sections.push(format!("## {heading}"));
+        }
+        previous_heading = block.heading();
+        sections.push(render_block(block, &render));
+    }
+
+    let mut doc = sections.join("\n\n");
+    if !doc.is_empty() {
+        doc.push('\n');
+    }
+    doc
+}
+
+const MIN_GUTTER_WIDTH: usize = 3;
+
+/// Prefixes each line with its number. With `selected`, only those
+/// block-relative lines are numbered; the rest get a blank gutter of the same
+/// width so the code stays aligned.
+fn add_line_numbers(
+    content: &str,
+    start_line: usize,
+    gutter_width: Option<usize>,
+    selected: Option<&[(usize, usize)]>,
+) -> String {
+    let width = gutter_width.unwrap_or_else(|| {
+        let last_line = start_line + line_count(content).saturating_sub(1);
+        last_line.to_string().len().max(MIN_GUTTER_WIDTH)
+    });
+
+    content
+        .lines()
+        .enumerate()
+        .map(|(idx, line)| {
+            let numbered = selected.is_none_or(|ranges| {
+                ranges
+                    .iter()
+                    .any(|&(start, end)| (start..=end).contains(&(idx + 1)))
+            });
+            if numbered {
+                format!("{:>width$}: {}", start_line + idx, line)
+            } else {
+                format!("{:width$}  {}", "", line)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn line_count(content: &str) -> usize {
+    if content.is_empty() {
+        0
+    } else {
+        content.lines().count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(name: &str, content: &str) -> InputSource {
+        InputSource {
+            name: name.to_string(),
+            content: content.to_string(),
+        }
+    }
+
+    fn with_inline() -> ParseOptions {
+        ParseOptions {
+            include_inline: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parses_fenced_block_with_lang() {
+        let blocks = collect_blocks(
+            vec![input("file.md", "```rust\nfn main() {}\n```\n")],
+            &ParseOptions::default(),
+        );
+        assert_eq!(blocks.len(), 1);
+        let b = &blocks[0];
+        assert_eq!(b.source, "file.md");
+        assert_eq!(b.kind, BlockKind::Fenced);
+        assert_eq!(b.lang.as_deref(), Some("rust"));
+        assert_eq!(b.code, "fn main() {}");
+        assert_eq!(b.start_line, Some(2));
+        assert_eq!(b.end_line, Some(2));
+        assert_eq!(b.fence_start_line, Some(1));
+        assert_eq!(b.fence_end_line, Some(3));
+        assert_eq!(b.index, 0);
+    }
+
+    #[test]
+    fn json_reports_fence_lines_with_line_numbers() {
+        let blocks = collect_blocks(
+            vec![input("file.md", "```rust\na\n```\n```sh\nb\n")],
+            &ParseOptions::default(),
+        );
+        let json = |b, line_numbers| {
+            let fields = JsonFields {
+                line_numbers,
+                ..Default::default()
+            };
+            serde_json::to_value(JsonBlock::new(b, fields)).unwrap()
+        };
+        let closed = json(&blocks[0], true);
+        assert_eq!(closed["fence_start_line"], 1);
+        assert_eq!(closed["fence_end_line"], 3);
+        let open = json(&blocks[1], true);
+        assert_eq!(open["fence_start_line"], 4);
+        assert!(open.get("fence_end_line").is_none());
+        assert!(json(&blocks[0], false).get("fence_start_line").is_none());
+    }
+
+    #[test]
+    fn parses_info_string_attributes() {
+        let (lang, attrs) = parse_info_string("rust {example=api-usage .runnable #intro}");
+        assert_eq!(lang.as_deref(), Some("rust"));
+        assert_eq!(attrs["example"], "api-usage");
+        assert_eq!(attrs["class"], "runnable");
+        assert_eq!(attrs["id"], "intro");
+
+        let (lang, attrs) = parse_info_string("{.python .numberLines title=\"a b.py\"}");
+        assert_eq!(lang.as_deref(), Some("python"));
+        assert_eq!(attrs["class"], "python numberLines");
+        assert_eq!(attrs["title"], "a b.py");
+
+        let (lang, attrs) = parse_info_string("rust ignore");
+        assert_eq!(lang.as_deref(), Some("rust"));
+        assert!(attrs.contains_key("ignore"));
+    }
+
+    #[test]
+    fn filters_blocks_by_attribute() {
+        let blocks = collect_blocks(
+            vec![input(
+                "a.md",
+                "```rust {example=api-usage .runnable}\na\n```\n```rust\nb\n```\n",
+            )],
+            &ParseOptions::default(),
+        );
+        assert_eq!(blocks[0].lang.as_deref(), Some("rust"));
+        assert!(matches_attr(&blocks[0], "example=api-usage"));
+        assert!(matches_attr(&blocks[0], "class=runnable"));
+        assert!(matches_attr(&blocks[0], "example"));
+        assert!(!matches_attr(&blocks[0], "example=other"));
+        assert!(!matches_attr(&blocks[1], "example"));
+    }
+
+    #[test]
+    fn parses_inline_blocks_when_enabled() {
+        let blocks = collect_blocks(vec![input("file.md", "a `one` b `two`")], &with_inline());
+        assert_eq!(blocks.len(), 2);
+        assert!(blocks.iter().all(|b| b.kind == BlockKind::Inline));
+        assert_eq!(blocks[0].code, "one");
+        assert_eq!(blocks[1].code, "two");
+        assert_eq!(blocks[0].start_line, Some(1));
+        assert_eq!(blocks[1].start_line, Some(1));
+    }
+
+    #[test]
+    fn inline_spans_keep_multibyte_text_intact() {
+        let blocks = parse_inline_blocks("a `café` b é`日本語`ü ``x`🦀`y``", 1, 0, "f.md");
+        let codes: Vec<&str> = blocks.iter().map(|b| b.code.as_str()).collect();
+        assert_eq!(codes, ["café", "日本語", "x`🦀`y"]);
+    }
+
+    #[test]
+    fn ignores_inline_when_flag_disabled() {
+        let blocks = collect_blocks(
+            vec![input("file.md", "a `one` b `two`")],
+            &ParseOptions::default(),
+        );
+        assert!(blocks.is_empty());
+    }
+
+    #[test]
+    fn handles_unterminated_fence() {
+        let blocks = collect_blocks(
+            vec![input("file.md", "```js\nconsole.log('x');")],
+            &ParseOptions::default(),
+        );
+        assert_eq!(blocks.len(), 1);
+        let b = &blocks[0];
+        assert_eq!(b.kind, BlockKind::Fenced);
+        assert_eq!(b.lang.as_deref(), Some("js"));
+        assert_eq!(b.start_line, Some(2));
+        assert_eq!(b.end_line, Some(2));
+        assert_eq!(b.fence_start_line, Some(1));
+        assert_eq!(b.fence_end_line, None);
+        assert_eq!(b.code, "console.log('x');");
+    }
+
+    #[test]
+    fn records_and_strips_fence_indent() {
+        let blocks = collect_blocks(
+            vec![input(
+                "list.md",
+                "- item\n  ```rust\n  fn a() {\n      b();\n  }\n  ```\n",
+            )],
+            &ParseOptions::default(),
+        );
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].indent, 2);
+        assert_eq!(blocks[0].code, "fn a() {\n    b();\n}");
+
+        let json = serde_json::to_value(JsonBlock::new(&blocks[0], JsonFields::default())).unwrap();
+        assert_eq!(json["indent"], 2);
+    }
+
+    #[test]
+    fn parses_blockquoted_fences() {
+        let doc = "> Example:\n>\n> ```rust\n> fn main() {\n>     run();\n> }\n> ```\n\n```\n> not quoted\n```\n";
+        let parse = ParseOptions {
+            blockquotes: true,
+            ..Default::default()
+        };
+        let blocks = collect_blocks(vec![input("q.md", doc)], &parse);
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].lang.as_deref(), Some("rust"));
+        assert_eq!(blocks[0].code, "fn main() {\n    run();\n}");
+        assert_eq!(blocks[0].fence_end_line, Some(7));
+        assert_eq!(blocks[1].code, "> not quoted");
+
+        let unquoted = collect_blocks(vec![input("q.md", doc)], &ParseOptions::default());
+        assert_eq!(unquoted.len(), 1);
+    }
+
+    #[test]
+    fn blockquote_end_closes_quoted_fence() {
+        let parse = ParseOptions {
+            blockquotes: true,
+            ..Default::default()
+        };
+        let blocks = collect_blocks(vec![input("q.md", "> ```\n> a\nafter\n")], &parse);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].code, "a");
+        assert_eq!(blocks[0].end_line, Some(2));
+        assert_eq!(blocks[0].fence_end_line, None);
+    }
+
+    #[test]
+    fn records_byte_spans_of_raw_content() {
+        let doc = "# T\r\n```rust\r\nfn a() {}\r\n```\r\nsee `x` here\n```\nopen";
+        let parse = ParseOptions {
+            include_inline: true,
+            ..Default::default()
+        };
+        let blocks = collect_blocks(vec![input("a.md", doc)], &parse);
+        let span = |b: &CodeBlock| &doc[b.byte_start.unwrap()..b.byte_end.unwrap()];
+        assert_eq!(span(&blocks[0]), "fn a() {}\r\n");
+        assert_eq!(span(&blocks[1]), "x");
+        assert_eq!(span(&blocks[2]), "open");
+    }
+
+    #[test]
+    fn joins_adjacent_same_language_blocks() {
+        let doc = "```rust\nfn a() {}\n```\nprose\n```Rust\nfn b() {}\n```\n```rust\nfn c() {}\n```\n```sh\nls\n```\n```rust\nfn d() {}\n```\n";
+        let blocks = collect_blocks(
+            vec![
+                input("a.md", doc),
+                input("b.md", "```rust\nfn e() {}\n```\n"),
+            ],
+            &ParseOptions::default(),
+        );
+        let joined = join_adjacent(blocks, &BTreeSet::new());
+        let codes: Vec<&str> = joined.iter().map(|b| b.code.as_str()).collect();
+        assert_eq!(
+            codes,
+            [
+                "fn a() {}\n\nfn b() {}\n\nfn c() {}",
+                "ls",
+                "fn d() {}",
+                "fn e() {}"
+            ]
+        );
+        assert_eq!(
+            (joined[0].start_line, joined[0].end_line),
+            (Some(2), Some(9))
+        );
+        assert_eq!(joined[0].hash, content_hash(&joined[0].code));
+    }
+
+    #[test]
+    fn join_adjacent_looks_past_inline_spans() {
+        let doc = "```rust\nfn a() {}\n```\nCall `a` first.\n```rust\nfn b() {}\n```\n";
+        let blocks = collect_blocks(vec![input("a.md", doc)], &with_inline());
+        let inline_spans = blocks
+            .iter()
+            .filter(|b| b.kind == BlockKind::Inline)
+            .map(|b| b.index)
+            .collect();
+        let joined = join_adjacent(blocks, &inline_spans);
+        let codes: Vec<&str> = joined.iter().map(|b| b.code.as_str()).collect();
+        assert_eq!(codes, ["fn a() {}\n\nfn b() {}", "a"]);
+    }
+
+    #[test]
+    fn shorter_fence_does_not_close_longer_one() {
+        let blocks = collect_blocks(
+            vec![input("file.md", "````\na\n```\nb\n`````\nafter\n")],
+            &ParseOptions::default(),
+        );
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].code, "a\n```\nb");
+        assert_eq!(blocks[0].end_line, Some(4));
+    }
+
+    #[test]
+    fn flags_unclosed_fences() {
+        let doc = "```\na\n```\n> ```\n> b\nafter `x`\n```sh\nc\n";
+        let parse = ParseOptions {
+            include_inline: true,
+            blockquotes: true,
+            ..Default::default()
+        };
+        let blocks = collect_blocks(vec![input("a.md", doc)], &parse);
+        let closed: Vec<bool> = blocks.iter().map(|b| b.closed).collect();
+        assert_eq!(closed, [true, false, true, false]);
+    }
+
+    #[test]
+    fn nested_fence_is_captured_verbatim() {
+        let doc = "````markdown\nExample:\n\n```rust\nfn main() {}\n```\n````\n\n```sh\nls\n```\n";
+        let blocks = collect_blocks(vec![input("a.md", doc)], &ParseOptions::default());
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].lang.as_deref(), Some("markdown"));
+        assert_eq!(blocks[0].code, "Example:\n\n```rust\nfn main() {}\n```");
+        assert_eq!(blocks[0].fence_end_line, Some(7));
+        assert_eq!(blocks[1].lang.as_deref(), Some("sh"));
+    }
+
+    #[test]
+    fn nested_tilde_fence_inside_backticks() {
+        let doc = "```md\n~~~python\nprint(1)\n~~~\n```\n";
+        let blocks = collect_blocks(vec![input("a.md", doc)], &ParseOptions::default());
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].code, "~~~python\nprint(1)\n~~~");
+    }
+
+    #[test]
+    fn closing_fence_rejects_info_string() {
+        assert!(is_closing_fence("````", '`', 4));
+        assert!(is_closing_fence("`````  ", '`', 4));
+        assert!(!is_closing_fence("```", '`', 4));
+        assert!(!is_closing_fence("```rust", '`', 3));
+        assert!(!is_closing_fence("~~~", '`', 3));
+    }
+
+    #[test]
+    fn assigns_indices_across_sources() {
+        let blocks = collect_blocks(
+            vec![input("a.md", "```txt\na\n```\n"), input("b.md", "text `x`")],
+            &with_inline(),
+        );
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].source, "a.md");
+        assert_eq!(blocks[0].index, 0);
+        assert_eq!(blocks[1].source, "b.md");
+        assert_eq!(blocks[1].index, 1);
+        assert_eq!(blocks[1].kind, BlockKind::Inline);
+    }
+
+    #[test]
+    fn wraps_non_markdown_inputs_with_as_code() {
+        let parse = ParseOptions {
+            as_code: true,
+            ..Default::default()
+        };
+        let blocks = collect_blocks(
+            vec![
+                input("build.sh", "set -e\nmake\n"),
+                input("notes.md", "```\nx\n```\n"),
+            ],
+            &parse,
+        );
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].lang.as_deref(), Some("bash"));
+        assert_eq!(blocks[0].code, "set -e\nmake");
+        assert_eq!(blocks[0].end_line, Some(2));
+        assert_eq!(blocks[1].code, "x");
+    }
+
+    #[test]
+    fn parses_rst_code_blocks() {
+        let doc = "Intro\n\n.. code-block:: python\n   :linenos:\n\n   def f():\n       return 1\n\n   f()\n\nProse with literal::\n\n    $ make\n\nEnd\n";
+        let blocks = collect_blocks(vec![input("a.rst", doc)], &ParseOptions::default());
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].lang.as_deref(), Some("python"));
+        assert_eq!(blocks[0].code, "def f():\n    return 1\n\nf()");
+        assert_eq!(
+            (blocks[0].start_line, blocks[0].end_line),
+            (Some(6), Some(9))
+        );
+        assert_eq!(blocks[1].lang, None);
+        assert_eq!(blocks[1].code, "$ make");
+    }
+
+    #[test]
+    fn parses_adoc_listings() {
+        let doc = "= Title\n\n[source,rust]\n.Example\n----\nfn main() {}\n----\n\n....\nliteral\n....\n[source, toml]\n----\nopen = true\n";
+        let blocks = collect_blocks(vec![input("a.adoc", doc)], &ParseOptions::default());
+        let langs: Vec<Option<&str>> = blocks.iter().map(|b| b.lang.as_deref()).collect();
+        assert_eq!(langs, [Some("rust"), None, Some("toml")]);
+        assert_eq!(blocks[0].code, "fn main() {}");
+        assert_eq!(blocks[0].start_line, Some(6));
+        assert_eq!(blocks[1].code, "literal");
+        assert!(!blocks[2].closed);
+    }
+
+    #[test]
+    fn reports_mixed_fence_delimiters() {
+        let blocks = collect_blocks(
+            vec![
+                input("a.md", "```\na\n```\n~~~\nb\n~~~\n"),
+                input("b.md", "~~~\nc\n~~~\n"),
+            ],
+            &ParseOptions::default(),
+        );
+        assert_eq!(
+            mixed_fences(&blocks, None),
+            ["a.md:4: tilde fence (document uses backtick fences)"]
+        );
+        assert_eq!(
+            mixed_fences(&blocks, Some('`')),
+            [
+                "a.md:4: tilde fence (required backtick fences)",
+                "b.md:1: tilde fence (required backtick fences)"
+            ]
+        );
+    }
+
+    #[test]
+    fn interleaves_warnings_with_blocks() {
+        let blocks = collect_blocks(
+            vec![
+                input("a.md", "```sh\na\n```\n"),
+                input("b.md", "x\n```rust\nb\n"),
+            ],
+            &ParseOptions::default(),
+        );
+        let sources = vec!["a.md".to_string(), "b.md".to_string()];
+        let warnings = vec![Warning {
+            source: Some("b.md".into()),
+            line: None,
+            message: "b.md contains bytes invalid in windows-1252".into(),
+        }];
+        let mut out = Vec::new();
+        write_tagged_ndjson(&mut out, &blocks, JsonFields::default(), &sources, warnings).unwrap();
+        let records: Vec<serde_json::Value> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        let types: Vec<&str> = records
+            .iter()
+            .map(|r| r["type"].as_str().unwrap())
+            .collect();
+        assert_eq!(types, ["block", "warning", "warning", "block"]);
+        assert_eq!(records[0]["lang"], "sh");
+        assert_eq!(records[2]["line"], 2);
+        assert!(
+            records[2]["message"]
+                .as_str()
+                .unwrap()
+                .contains("unterminated")
+        );
+        assert_eq!(records[3]["code"], "b");
+    }
+
+    #[test]
+    fn reports_fence_lengths() {
+        let blocks = collect_blocks(
+            vec![input("a.md", "```\na\n```\n\n````md\n```\nb\n```\n````\n")],
+            &ParseOptions::default(),
+        );
+        assert_eq!(
+            wrong_fence_lengths(&blocks, 3),
+            ["a.md:5: fence is 4 characters long (required 3)"]
+        );
+        assert!(wrong_fence_lengths(&blocks[..1], 3).is_empty());
+
+        let parse = |len: &str| Cli::try_parse_from(["mdcode", "--require-fence-len", len]);
+        assert_eq!(parse("4").unwrap().args.require_fence_len, Some(4));
+        assert!(parse("2").is_err());
+    }
+
+    #[test]
+    fn raw_bytes_keep_line_endings_and_blank_lines() {
+        let doc = "a\r\n```sh\r\necho\r\n\r\n```\r\n";
+        let blocks = collect_blocks(vec![input("a.md", doc)], &ParseOptions::default());
+        let sources = BTreeMap::from([("a.md".to_string(), doc.to_string())]);
+        assert_eq!(blocks[0].code, "echo");
+        assert_eq!(source_span(&blocks[0], &sources).unwrap(), "echo\r\n\r\n");
+        assert!(source_span(&blocks[0], &BTreeMap::new()).is_err());
+    }
+
+    #[test]
+    fn extracts_code_from_table_cells() {
+        let doc = "# API\n\n| Call | Returns |\n|---|:---:|\n| `open(p)` | `File` |\n| plain | `a \\| b` |\n\nAfter `x`\n";
+        let parse = ParseOptions {
+            tables: true,
+            ..Default::default()
+        };
+        let blocks = collect_blocks(vec![input("a.md", doc)], &parse);
+        let cells: Vec<(&str, Option<usize>, Option<usize>)> = blocks
+            .iter()
+            .map(|b| (b.code.as_str(), b.row, b.col))
+            .collect();
+        assert_eq!(
+            cells,
+            [
+                ("open(p)", Some(1), Some(0)),
+                ("File", Some(1), Some(1)),
+                ("a | b", Some(2), Some(1))
+            ]
+        );
+        assert_eq!(blocks[0].start_line, Some(5));
+        assert_eq!(blocks[0].heading_path, ["API"]);
+        assert_eq!(
+            &doc[blocks[1].byte_start.unwrap()..blocks[1].byte_end.unwrap()],
+            "File"
+        );
+
+        // With --inline too, table lines are not extracted twice.
+        let both = ParseOptions {
+            include_inline: true,
+            ..parse
+        };
+        let blocks = collect_blocks(vec![input("a.md", doc)], &both);
+        assert_eq!(blocks.len(), 4);
+        assert_eq!((blocks[3].code.as_str(), blocks[3].row), ("x", None));
+    }
+
+    #[test]
+    fn extracts_between_custom_delimiters() {
+        let doc =
+            "intro\n<<<code python\nprint(1)\n<<<code\n>>>\n```rust\nignored\n```\n<<<code\ntail\n";
+        let parse = ParseOptions {
+            between: Some(("<<<code".into(), ">>>".into())),
+            ..Default::default()
+        };
+        let blocks = collect_blocks(vec![input("a.txt", doc)], &parse);
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].lang.as_deref(), Some("python"));
+        assert_eq!(blocks[0].code, "print(1)\n<<<code");
+        assert_eq!(
+            (blocks[0].start_line, blocks[0].fence_end_line),
+            (Some(3), Some(5))
+        );
+        assert_eq!(blocks[1].code, "tail");
+        assert!(!blocks[1].closed);
+
+        let parse =
+            |open: &str, close: &str| Cli::try_parse_from(["mdcode", "--between", open, close]);
+        assert!(parse("<<<", ">>>").is_ok());
+        assert!(parse("", ">>>").is_err());
+        assert!(parse("<<<", "  ").is_err());
+    }
+
+    #[test]
+    fn parses_notebook_code_cells() {
+        let notebook = r##"{"metadata": {"kernelspec": {"language": "python"}},
+            "cells": [
+                {"cell_type": "markdown", "source": ["# Title"]},
+                {"cell_type": "code", "source": ["import os\n", "print(os.sep)\n"]},
+                {"cell_type": "code", "source": "x = 1"}
+            ]}"##;
+        let blocks = collect_blocks(vec![input("n.ipynb", notebook)], &ParseOptions::default());
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].lang.as_deref(), Some("python"));
+        assert_eq!(blocks[0].code, "import os\nprint(os.sep)");
+        assert_eq!(blocks[1].code, "x = 1");
+    }
+
+    #[test]
+    fn parses_rustdoc_comment_blocks() {
+        let source = r#"//! Crate docs.
+//!
+//! ```
+//! let x = mylib::new();
+//! ```
+
+/// Adds one.
+///
+/// ```no_run
+/// # use mylib::add_one;
+/// assert_eq!(add_one(1), 2);
+/// ```
+//// not a doc comment
+pub fn add_one(x: i32) -> i32 {
+    // ```text
+    x + 1
+}
+
+impl A {
+    /// ```toml
+    /// key = 1
+    /// ```
+    fn f() {}
+}
+"#;
+        let parse = ParseOptions {
+            format: Some(InputFormat::Rustdoc),
+            ..Default::default()
+        };
+        let blocks = collect_blocks(vec![input("src/lib.rs", source)], &parse);
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks[0].lang.as_deref(), Some("rust"));
+        assert_eq!(blocks[0].code, "let x = mylib::new();");
+        assert_eq!(
+            (blocks[0].fence_start_line, blocks[0].start_line),
+            (Some(3), Some(4))
+        );
+        assert_eq!(blocks[1].lang.as_deref(), Some("rust"));
+        assert_eq!(
+            blocks[1].code,
+            "# use mylib::add_one;\nassert_eq!(add_one(1), 2);"
+        );
+        assert_eq!(
+            (
+                blocks[1].start_line,
+                blocks[1].end_line,
+                blocks[1].fence_end_line
+            ),
+            (Some(10), Some(11), Some(12))
+        );
+        assert_eq!(blocks[2].lang.as_deref(), Some("toml"));
+        assert_eq!(blocks[2].start_line, Some(21));
+        assert_eq!(blocks[2].byte_start, None);
+    }
+
+    #[test]
+    fn forced_format_overrides_extension() {
+        let parse = ParseOptions {
+            format: Some(InputFormat::Markdown),
+            ..Default::default()
+        };
+        let blocks = collect_blocks(vec![input("notes.rst", "```sh\nls\n```\n")], &parse);
+        assert_eq!(blocks[0].lang.as_deref(), Some("sh"));
+        assert_eq!(InputFormat::detect("x.txt"), None);
+        assert_eq!(InputFormat::detect("README"), Some(InputFormat::Markdown));
+        assert_eq!(InputFormat::detect("book.ADOC"), Some(InputFormat::Adoc));
+    }
+
+    #[test]
+    fn caps_blocks_per_source() {
+        let blocks = collect_blocks(
+            vec![
+                input("a.md", "```\n1\n```\n```\n2\n```\n```\n3\n```\n"),
+                input("b.md", "```\n4\n```\n"),
+            ],
+            &ParseOptions::default(),
+        );
+        let capped = cap_per_source(blocks, 2);
+        let indices: Vec<usize> = capped.iter().map(|b| b.index).collect();
+        assert_eq!(indices, [0, 1, 3]);
+    }
+
+    #[test]
+    fn keeps_first_block_per_language() {
+        let blocks = collect_blocks(
+            vec![input(
+                "a.md",
+                "```sh\n1\n```\n```\n2\n```\n```Bash\n3\n```\n```rust\n4\n```\n```sh\n5\n```\n```\n6\n```\n",
+            )],
+            &ParseOptions::default(),
+        );
+        let aliases = BTreeMap::from([("sh".to_string(), "bash".to_string())]);
+        let kept = first_per_lang(blocks, &aliases);
+        let indices: Vec<usize> = kept.iter().map(|b| b.index).collect();
+        assert_eq!(indices, [0, 1, 3]);
+    }
+
+    #[test]
+    fn explain_names_the_dropping_filter() {
+        let doc = "```rust\nlet a = 1;\n```\n```sh\nls\n```\n```rust\nfn b() {}\n```\n";
+        let mut blocks = collect_blocks(vec![input("a.md", doc)], &ParseOptions::default());
+        let mut trace = Some(FilterTrace::new(&blocks));
+        blocks.retain(|b| b.lang.as_deref() == Some("rust"));
+        explain_step(&mut trace, "--lang", &blocks);
+        let trace = trace.as_mut().unwrap();
+        trace.renumber(&blocks);
+        for (index, block) in blocks.iter_mut().enumerate() {
+            block.index = index;
+        }
+        blocks.retain(|b| b.code.contains("fn"));
+        trace.step("--grep", &blocks);
+        assert_eq!(
+            trace.lines(),
+            [
+                "block 0 a.md:2 (rust): dropped by --grep (passed --lang)",
+                "block 1 a.md:5 (sh): dropped by --lang",
+                "block 2 a.md:8 (rust): kept (passed --lang, --grep)",
+            ]
+        );
+    }
+
+    #[test]
+    fn seeded_sample_is_reproducible_and_ordered() {
+        let doc: String = (0..20).map(|n| format!("```\n{n}\n```\n")).collect();
+        let blocks = || collect_blocks(vec![input("a.md", &doc)], &ParseOptions::default());
+        let indices = |seed| -> Vec<usize> {
+            sample_blocks(blocks(), 5, Some(seed))
+                .iter()
+                .map(|b| b.index)
+                .collect()
+        };
+        let first = indices(7);
+        assert_eq!(first.len(), 5);
+        assert!(first.is_sorted());
+        assert_eq!(indices(7), first);
+        assert_eq!(sample_blocks(blocks(), 50, None).len(), 20);
+    }
+
+    #[test]
+    fn where_expression_selects_blocks() {
+        let blocks = collect_blocks(
+            vec![input(
+                "a.md",
+                "```rust\n1\n2\n3\n```\n```rs\n1\n```\n```sh\n1\n2\n```\n",
+            )],
+            &ParseOptions::default(),
+        );
+        let aliases = BTreeMap::from([("rs".to_string(), "rust".to_string())]);
+        let select = |raw: &str| -> Vec<usize> {
+            let expr = parse_where(raw).unwrap();
+            blocks
+                .iter()
+                .filter(|b| expr.matches(b, &aliases))
+                .map(|b| b.index)
+                .collect()
+        };
+        assert_eq!(select("lang==rust && lines>2"), [0]);
+        assert_eq!(select("lang == 'rust'"), [0, 1]);
+        assert_eq!(select("lang==sh || index<1 && kind==fenced"), [0, 2]);
+        assert_eq!(select("(lang!=rust || lines<2) && source==a.md"), [1, 2]);
+
+        assert_eq!(
+            parse_where("lang==rust &&").unwrap_err(),
+            "expected a field at end of expression"
+        );
+        assert_eq!(
+            parse_where("lines>ten").unwrap_err(),
+            "expected a number but found `ten` at column 7"
+        );
+        assert_eq!(
+            parse_where("langs==rust").unwrap_err(),
+            "unknown field `langs` at column 1 (expected lang, kind, lines, source or index)"
+        );
+        assert_eq!(
+            parse_where("lang==rust rust").unwrap_err(),
+            "unexpected `rust` at column 12"
+        );
+    }
+
+    #[test]
+    fn reports_lines_over_the_limit() {
+        let doc = "text\n```\nshort\nnaïve café ü\n// see https://example.com/a/long/path\n```\n";
+        let blocks = collect_blocks(vec![input("a.md", doc)], &ParseOptions::default());
+        assert_eq!(
+            long_lines(&blocks, 12, false),
+            ["a.md:5: line is 38 characters (max 12)"]
+        );
+        assert_eq!(
+            long_lines(&blocks, 11, true),
+            ["a.md:4: line is 12 characters (max 11)"]
+        );
+    }
+
+    #[test]
+    fn context_stops_at_files_and_fences() {
+        let doc = "Intro\nSetup:\n```sh\nmake\n```\nThen run:\n```sh\n./app\n```\nDone.\n";
+        let sources = BTreeMap::from([("a.md".to_string(), doc.to_string())]);
+        let mut blocks = collect_blocks(vec![input("a.md", doc)], &ParseOptions::default());
+        attach_context(&mut blocks, &sources, 3, 0);
+        assert_eq!(blocks[0].context_before, ["Intro", "Setup:"]);
+        assert_eq!(blocks[1].context_before, ["Then run:"]);
+        assert!(blocks[1].context_after.is_empty());
+        attach_context(&mut blocks, &sources, 0, 2);
+        assert_eq!(blocks[0].context_after, ["Then run:"]);
+        assert_eq!(blocks[1].context_after, ["Done."]);
+        assert_eq!(
+            render_block(&blocks[1], &RenderOptions::default()),
+            "./app\nDone."
+        );
+    }
+
+    #[test]
+    fn reports_tabs_in_block_lines_only() {
+        let doc = "prose\twith tab\n```go\nfunc a() {\n\treturn\n}\n```\n";
+        let blocks = collect_blocks(vec![input("a.md", doc)], &ParseOptions::default());
+        assert_eq!(tab_lines(&blocks), ["a.md:4: tab character at column 1"]);
+    }
+
+    #[test]
+    fn finds_imports_per_language() {
+        let doc = "```rust\nuse std::io::{self, Read};\npub use crate::x;\nextern crate serde;\nlet user = 1;\n```\n```python\nimport os, sys as system\nfrom collections import OrderedDict\n```\n```js\nimport React from 'react';\nimport './style.css';\nconst fs = require(\"fs\");\n```\n```go\nimport \"fmt\"\n```\n";
+        let blocks = collect_blocks(vec![input("a.md", doc)], &ParseOptions::default());
+        let aliases = BTreeMap::new();
+        let found: Vec<Vec<String>> = blocks.iter().map(|b| block_imports(b, &aliases)).collect();
+        assert_eq!(found[0], ["crate::x", "serde", "std::io::{self, Read}"]);
+        assert_eq!(found[1], ["collections", "os", "sys"]);
+        assert_eq!(found[2], ["./style.css", "fs", "react"]);
+        assert!(found[3].is_empty());
+    }
+
+    #[test]
+    fn describes_backtick_runs() {
+        let line = "a `x` and ``y` z";
+        let spans = parse_inline_blocks(line, 1, 0, "a.md").len();
+        assert_eq!(
+            inline_debug_line(line, spans).as_deref(),
+            Some("ticks=[1,1,2,1] -> 1 span")
+        );
+        assert_eq!(
+            inline_debug_line("`a` `b`", 2).as_deref(),
+            Some("ticks=[1,1,1,1] -> 2 spans")
+        );
+        assert_eq!(inline_debug_line("no ticks", 0), None);
+    }
+
+    #[test]
+    fn transforms_blocks_through_commands() {
+        assert_eq!(
+            run_transform("tr a-z A-Z", "fn a() {}").unwrap(),
+            "FN A() {}"
+        );
+        assert_eq!(run_transform("cat; echo tail", "x").unwrap(), "x\ntail");
+        assert!(run_transform("exit 3", "x").is_err());
+    }
+
+    #[test]
+    fn extracts_named_regions() {
+        let doc = "```rust\nfn setup() {}\n// BEGIN main\nfn main() {\n    run();\n}\n// END main\n/* BEGIN main-extra */\nx\n/* END main-extra */\n```\n";
+        let mut blocks = collect_blocks(vec![input("a.md", doc)], &ParseOptions::default());
+        let mut missing = collect_blocks(
+            vec![input("b.md", "```\nno markers\n```\n")],
+            &ParseOptions::default(),
+        );
+
+        assert!(extract_region(&mut blocks[0], Some(doc), "main"));
+        assert_eq!(blocks[0].code, "fn main() {\n    run();\n}");
+        assert_eq!(
+            (blocks[0].start_line, blocks[0].end_line),
+            (Some(4), Some(6))
+        );
+        let span = &doc[blocks[0].byte_start.unwrap()..blocks[0].byte_end.unwrap()];
+        assert_eq!(span, "fn main() {\n    run();\n}\n");
+        assert!(!extract_region(&mut missing[0], None, "main"));
+        assert_eq!(missing[0].code, "no markers");
+    }
+
+    #[test]
+    fn strips_one_leading_blank_line() {
+        let doc = "```\n\n\nfn a() {}\n```\n```\n\n```\n```\nx\n```\n";
+        let mut blocks = collect_blocks(vec![input("a.md", doc)], &ParseOptions::default());
+        for block in &mut blocks {
+            strip_leading_blank(block, Some(doc));
+        }
+        assert_eq!(blocks[0].code, "\nfn a() {}");
+        assert_eq!(blocks[0].start_line, Some(3));
+        let span = &doc[blocks[0].byte_start.unwrap()..blocks[0].byte_end.unwrap()];
+        assert_eq!(span, "\nfn a() {}\n");
+        assert_eq!(blocks[1].code, "");
+        assert_eq!(blocks[2].code, "x");
+        assert_eq!(blocks[2].start_line, Some(10));
+    }
+
+    #[test]
+    fn indents_rendered_blocks_but_not_blank_lines() {
+        let blocks = collect_blocks(
+            vec![input("a.md", "```sh\n    make\n\n      install\n```\n")],
+            &ParseOptions::default(),
+        );
+        let mut block = blocks.into_iter().next().unwrap();
+        block.code = dedent(&block.code);
+        let render = RenderOptions {
+            fenced: true,
+            indent: 3,
+            ..Default::default()
+        };
+        assert_eq!(
+            render_block(&block, &render),
+            "   ```sh\n   make\n\n     install\n   ```"
+        );
+    }
+
+    #[test]
+    fn dedents_common_leading_whitespace() {
+        assert_eq!(dedent("    a\n      b\n\n    c"), "a\n  b\n\nc");
+        assert_eq!(dedent("    a\n    b"), "a\nb");
+        assert_eq!(dedent("a\n    b"), "a\n    b");
+        assert_eq!(dedent("\ta\n    b"), "\ta\n    b");
+    }
+
+    #[test]
+    fn detects_blank_blocks() {
+        let blocks = collect_blocks(
+            vec![input("a.md", "```\n```\n```\n  \n\t\n```\n```\nx\n```\n")],
+            &ParseOptions::default(),
+        );
+        let blank: Vec<bool> = blocks.iter().map(is_blank_block).collect();
+        assert_eq!(blank, [true, true, false]);
+    }
+
+    #[test]
+    fn matches_lang_case_insensitive() {
+        let block = CodeBlock {
+            index: 0,
+            source: "file.md".into(),
+            kind: BlockKind::Fenced,
+            lang: Some("Rust".into()),
+            start_line: None,
+            end_line: None,
+            code: String::new(),
+            ..Default::default()
+        };
+        let aliases = BTreeMap::new();
+        assert!(matches_lang(&block, "rust", &aliases));
+        assert!(!matches_lang(&block, "python", &aliases));
+    }
+
+    #[test]
+    fn matches_lang_through_aliases() {
+        let block = CodeBlock {
+            index: 0,
+            source: "file.md".into(),
+            kind: BlockKind::Fenced,
+            lang: Some("rs".into()),
+            start_line: None,
+            end_line: None,
+            code: String::new(),
+            ..Default::default()
+        };
+        let aliases = BTreeMap::from([("rs".to_string(), "rust".to_string())]);
+        assert!(matches_lang(&block, "rust", &aliases));
+        assert!(matches_lang(&block, "RS", &aliases));
+    }
+
+    #[test]
+    fn reports_disallowed_languages() {
+        let blocks = collect_blocks(
+            vec![input(
+                "doc.md",
+                "```rust\na\n```\n```\nb\n```\n```python\nc\n```\n`inline`\n",
+            )],
+            &with_inline(),
+        );
+        let aliases = BTreeMap::new();
+        let allowed = ["rust".to_string(), "toml".to_string()];
+        assert_eq!(
+            lang_violations(&blocks, &allowed, &aliases),
+            [
+                "doc.md:4: fenced block has no language",
+                "doc.md:7: language 'python' is not allowed",
+            ]
+        );
+        assert_eq!(lang_violations(&blocks, &[], &aliases).len(), 1);
+    }
+
+    #[test]
+    fn schema_describes_optional_fields_and_kinds() {
+        let schema = serde_json::to_value(schemars::schema_for!(JsonBlock)).unwrap();
+        let required = schema["required"].as_array().unwrap();
+        assert!(required.iter().any(|f| f == "code"));
+        assert!(!required.iter().any(|f| f == "start_line"));
+        assert_eq!(
+            schema["$defs"]["BlockKind"]["enum"],
+            serde_json::json!(["fenced", "inline"])
+        );
+    }
+
+    #[test]
+    fn collates_blocks_by_canonical_language() {
+        let blocks = collect_blocks(
+            vec![input(
+                "a.md",
+                "```rs\na\n```\n```python\nb\n```\n```rust\nc\n```\n```\nd\n```\n",
+            )],
+            &ParseOptions::default(),
+        );
+        let aliases = BTreeMap::from([("rs".to_string(), "rust".to_string())]);
+        let groups = collate(&blocks, &aliases);
+        assert_eq!(
+            groups.keys().collect::<Vec<_>>(),
+            ["plain", "python", "rust"]
+        );
+
+        let text = render_collated(&groups, &RenderOptions::default(), "\n");
+        assert_eq!(text, "## plain\n\nd\n\n## python\n\nb\n\n## rust\n\na\nc");
+    }
+
+    #[test]
+    fn diffs_changed_added_and_removed_blocks() {
+        let ours = collect_blocks(
+            vec![input("a.md", "```rust\nlet a = 1;\n```\n```sh\nls\n```\n")],
+            &ParseOptions::default(),
+        );
+        let theirs = collect_blocks(
+            vec![input(
+                "b.md",
+                "```sh\nls\n```\n```rust\nlet a = 2;\n```\n```toml\nx = 1\n```\n",
+            )],
+            &ParseOptions::default(),
+        );
+
+        let report = diff_blocks(&ours, &theirs, DiffKey::LangPosition);
+        assert!(report.contains("-let a = 1;\n+let a = 2;\n"));
+        assert!(report.contains("block toml#0 added: b.md:8 (toml)"));
+        assert!(!report.contains("sh#0"));
+
+        assert!(diff_blocks(&ours, &ours, DiffKey::Index).is_empty());
+        let by_index = diff_blocks(&ours, &theirs[..1], DiffKey::Index);
+        assert!(by_index.contains("block #1 removed: a.md:5 (sh)"));
+    }
+
+    #[test]
+    fn version_json_lists_formats_and_features() {
+        let json = serde_json::to_value(VersionInfo::current()).unwrap();
+        assert_eq!(json["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(
+            json["formats"],
+            serde_json::json!(["markdown", "rst", "adoc", "ipynb", "rustdoc"])
+        );
+        let features = json["features"].as_array().unwrap();
+        assert!(features.contains(&"json".into()));
+        assert_eq!(
+            features.contains(&"remote".into()),
+            cfg!(feature = "reqwest")
+        );
+    }
+
+    #[test]
+    fn subcommands_select_modes_and_bare_form_still_parses() {
+        let parse = |argv: &[&str]| {
+            let cli = Cli::try_parse_from(argv).unwrap();
+            match cli.command {
+                Some(command) => command.into_args(),
+                None => cli.args,
+            }
+        };
+
+        let args = parse(&["mdcode", "list", "--inline", "a.md"]);
+        assert!(args.list && args.inline);
+        assert_eq!(args.files, [PathBuf::from("a.md")]);
+        assert!(matches!(
+            parse(&["mdcode", "langs", "a.md"]).lang,
+            Some(None)
+        ));
+        assert!(parse(&["mdcode", "stats", "a.md"]).stats);
+        assert!(parse(&["mdcode", "--list", "a.md"]).list);
+        assert!(!parse(&["mdcode", "extract", "a.md"]).list);
+        // After a top-level option, subcommand names are ordinary file names.
+        assert_eq!(
+            parse(&["mdcode", "--inline", "list"]).files,
+            [PathBuf::from("list")]
+        );
+    }
+
+    #[test]
+    fn lists_distinct_languages() {
+        let blocks = collect_blocks(
+            vec![input(
+                "a.md",
+                "```sh\na\n```\n```\nb\n```\n```rust\nc\n```\n```sh\nd\n```\n",
+            )],
+            &ParseOptions::default(),
+        );
+        assert_eq!(
+            languages(&blocks).into_iter().collect::<Vec<_>>(),
+            ["rust", "sh"]
+        );
+        assert!(languages(&blocks[1..2]).is_empty());
+    }
+
+    #[test]
+    fn renders_stats_per_language() {
+        let blocks = collect_blocks(
+            vec![input(
+                "a.md",
+                "```rust\na\nb\n```\n```sh\nc\n```\n```rust\nd\n```\n",
+            )],
+            &ParseOptions::default(),
+        );
+        assert_eq!(
+            render_stats(&blocks, &BTreeMap::new()),
+            "blocks: 3\nlines: 4\nchars: 5\nbytes: 5\nrust: 2 blocks (3 lines, 4 chars, 4 bytes)\nsh: 1 blocks (1 lines, 1 chars, 1 bytes)\n"
+        );
+    }
+
+    #[test]
+    fn stats_count_chars_and_bytes_separately() {
+        let blocks = collect_blocks(
+            vec![input("a.md", "```text\nnaïve café\n```\n")],
+            &ParseOptions::default(),
+        );
+        let volume = Volume::of(&blocks);
+        assert_eq!((volume.chars, volume.bytes), (10, 12));
+        assert!(render_stats(&blocks, &BTreeMap::new()).contains("chars: 10\nbytes: 12\n"));
+    }
+
+    #[test]
+    fn envelope_carries_run_metadata() {
+        let blocks = collect_blocks(
+            vec![input("a.md", "```\na\n```\n")],
+            &ParseOptions::default(),
+        );
+        let json =
+            serde_json::to_value(Envelope::new(&blocks, JsonFields::default(), true)).unwrap();
+        assert_eq!(json["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(json["count"], 1);
+        assert_eq!(json["blocks"][0]["code"], "a");
+        let stamp = json["generated_at"].as_str().unwrap();
+        assert!(humantime::parse_rfc3339(stamp).is_ok());
+
+        let json =
+            serde_json::to_value(Envelope::new(&blocks, JsonFields::default(), false)).unwrap();
+        assert!(json.get("generated_at").is_none());
+    }
+
+    #[test]
+    fn reproducible_envelopes_are_stable_across_runs_and_platforms() {
+        let envelope = |name: &str| {
+            let blocks = collect_blocks(
+                vec![input(&portable_name(name), "```\na\n```\n")],
+                &ParseOptions::default(),
+            );
+            serde_json::to_string(&Envelope::new(&blocks, JsonFields::default(), false)).unwrap()
+        };
+        assert_eq!(portable_name("docs\\guide\\a.md"), "docs/guide/a.md");
+        let windows = envelope("docs\\a.md");
+        assert_eq!(windows, envelope("docs/a.md"));
+        assert!(windows.contains("\"source\":\"docs/a.md\""));
+        assert!(!windows.contains("generated_at"));
+    }
+
+    #[test]
+    fn inventories_languages() {
+        let blocks = collect_blocks(
+            vec![
+                input("a.md", "```rust\na\n```\n`x`\n"),
+                input("b.md", "```rust\nb\n```\n```sh\nc\n```\n"),
+            ],
+            &with_inline(),
+        );
+        let json = serde_json::to_value(language_inventory(&blocks)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!([
+                {"lang": "rust", "count": 2, "fenced": 2, "inline": 0, "sources": ["a.md", "b.md"]},
+                {"lang": "sh", "count": 1, "fenced": 1, "inline": 0, "sources": ["b.md"]},
+                {"lang": null, "count": 1, "fenced": 0, "inline": 1, "sources": ["a.md"]},
+            ])
+        );
+    }
+
+    #[test]
+    fn summary_is_complete_without_blocks() {
+        let sources = vec!["a.md".to_string()];
+        let summary = serde_json::to_value(Summary::new(&[], &sources, &BTreeMap::new())).unwrap();
+        assert_eq!(
+            summary,
+            serde_json::json!({
+                "blocks": 0,
+                "lines": 0,
+                "chars": 0,
+                "bytes": 0,
+                "languages": {},
+                "language_volume": {},
+                "kinds": {"fenced": 0, "inline": 0},
+                "sources": ["a.md"],
+            })
+        );
+
+        let blocks = collect_blocks(
+            vec![input("a.md", "```rust\na\nb\n```\n`x`\n")],
+            &with_inline(),
+        );
+        let summary =
+            serde_json::to_value(Summary::new(&blocks, &sources, &BTreeMap::new())).unwrap();
+        assert_eq!(
+            summary["languages"],
+            serde_json::json!({"plain": 1, "rust": 1})
+        );
+        assert_eq!(
+            summary["kinds"],
+            serde_json::json!({"fenced": 1, "inline": 1})
+        );
+        assert_eq!(summary["lines"], 3);
+
+        let blocks = collect_blocks(
+            vec![input("a.md", "```txt\nnaïve ✓\n```\n")],
+            &ParseOptions::default(),
+        );
+        let summary =
+            serde_json::to_value(Summary::new(&blocks, &sources, &BTreeMap::new())).unwrap();
+        assert_eq!(
+            (summary["chars"].clone(), summary["bytes"].clone()),
+            (7.into(), 10.into())
+        );
+        assert_eq!(
+            summary["language_volume"]["txt"],
+            serde_json::json!({"lines": 1, "chars": 7, "bytes": 10})
+        );
+    }
+
+    #[test]
+    fn config_fills_unset_args() {
+        let config: Config =
+            toml::from_str("inline = true\nseparator = \"---\"\nlang = \"rust\"").unwrap();
+        let matches = Cli::command().get_matches_from(["mdcode", "--sep", ";", "a.md"]);
+        let mut args = Cli::from_arg_matches(&matches).unwrap().args;
+        config.apply(&mut args, &matches);
+
+        assert!(args.inline);
+        assert_eq!(args.separator, ";");
+        assert!(matches!(args.lang, Some(Some(ref l)) if l == "rust"));
+    }
+
+    #[test]
+    fn parses_index_filters() {
+        match parse_index_filter(Some("3")).unwrap() {
+            Some(IndexFilter::Single(3)) => {}
+            other => panic!("unexpected: {:?}", other),
+        }
+
+        match parse_index_filter(Some("1-4")).unwrap() {
+            Some(IndexFilter::Range { start, end }) => {
+                assert_eq!(start, 1);
+                assert_eq!(end, 4);
+            }
+            other => panic!("unexpected: {:?}", other),
+        }
+
+        assert!(parse_index_filter(Some("4-2")).is_err());
+    }
+
+    #[test]
+    fn slices_block_lines_and_keeps_source_numbers() {
+        let doc = "```\n1\n2\n3\n4\n```\n";
+        let mut blocks = collect_blocks(vec![input("a.md", doc)], &ParseOptions::default());
+        slice_lines(&mut blocks[0], Some(doc), 2, 3);
+        assert_eq!(blocks[0].code, "2\n3");
+        assert_eq!(blocks[0].start_line, Some(3));
+        assert_eq!(blocks[0].end_line, Some(4));
+        let span = &doc[blocks[0].byte_start.unwrap()..blocks[0].byte_end.unwrap()];
+        assert_eq!(span, "2\n3\n");
+
+        let doc = "```\n1\n2\n```\n";
+        let mut blocks = collect_blocks(vec![input("a.md", doc)], &ParseOptions::default());
+        slice_lines(&mut blocks[0], Some(doc), 2, 9);
+        assert_eq!(blocks[0].code, "2");
+        let span = &doc[blocks[0].byte_start.unwrap()..blocks[0].byte_end.unwrap()];
+        assert_eq!(span, "2\n");
+
+        assert!(parse_line_range("0-3").is_err());
+        assert_eq!(parse_line_range("2-5").unwrap(), (2, 5));
+    }
+
+    #[test]
+    fn renders_fenced_with_line_numbers() {
+        let block = CodeBlock {
+            index: 0,
+            source: "file.md".into(),
+            kind: BlockKind::Fenced,
+            lang: Some("rs".into()),
+            start_line: Some(10),
+            end_line: Some(11),
+            code: "fn a() {}\nfn b() {}".into(),
+            ..Default::default()
+        };
+
+        let render = RenderOptions {
+            fenced: true,
+            line_numbers: true,
+            ..Default::default()
+        };
+        let rendered = render_block(&block, &render);
+        let expected = "```rs\n 10: fn a() {}\n 11: fn b() {}\n```";
+        assert_eq!(rendered, expected);
+    }
+
+    #[test]
+    fn fence_char_overrides_delimiter() {
+        let block = CodeBlock {
+            lang: Some("md".into()),
+            code: "~~~\n```".into(),
+            ..Default::default()
+        };
+        let render = RenderOptions {
+            fenced: true,
+            fence_char: FenceChar::Tilde,
+            ..Default::default()
+        };
+        assert_eq!(render_block(&block, &render), "~~~~md\n~~~\n```\n~~~~");
+    }
+
+    #[test]
+    fn show_fences_keeps_original_delimiters() {
+        let doc = "  ~~~~python title=x\n  print(1)\n  ~~~~~\n```sh\nls\n";
+        let blocks = collect_blocks(vec![input("a.md", doc)], &ParseOptions::default());
+        let render = RenderOptions {
+            show_fences: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            render_block(&blocks[0], &render),
+            "  ~~~~python title=x\n  print(1)\n  ~~~~~"
+        );
+        assert_eq!(render_block(&blocks[1], &render), "```sh\nls");
+    }
+
+    #[test]
+    fn number_start_overrides_source_line() {
+        let block = CodeBlock {
+            index: 0,
+            source: "file.md".into(),
+            kind: BlockKind::Fenced,
+            lang: None,
+            start_line: Some(10),
+            end_line: Some(11),
+            code: "a\nb".into(),
+            ..Default::default()
+        };
+
+        let render = RenderOptions {
+            line_numbers: true,
+            number_start: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(render_block(&block, &render), "  1: a\n  2: b");
+    }
+
+    #[test]
+    fn records_trailing_blank_lines() {
+        let blocks = collect_blocks(
+            vec![input("file.md", "```\na\n\n\n```\n```\nb\n```\n")],
+            &ParseOptions::default(),
+        );
+        assert_eq!(blocks[0].code, "a");
+        assert_eq!(blocks[0].trailing_newlines, 2);
+        assert_eq!(blocks[1].trailing_newlines, 0);
+
+        let render = RenderOptions {
+            fenced: true,
+            preserve_trailing: true,
+            ..Default::default()
+        };
+        assert_eq!(render_block(&blocks[0], &render), "```\na\n\n\n```");
+    }
+
+    #[test]
+    fn peek_elides_middle_lines() {
+        let block = CodeBlock {
+            lang: Some("txt".into()),
+            source: "file.md".into(),
+            code: "1\n2\n3\n4\n5".into(),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            render_peek(&block, 1, false, false),
+            "0: txt (5 lines) [file.md]\n  1\n  ... (3 more lines)\n  5"
+        );
+        assert_eq!(render_peek(&block, 3, false, false).lines().count(), 6);
+    }
+
+    #[test]
+    fn captures_nearest_heading() {
+        let blocks = collect_blocks(
+            vec![input(
+                "file.md",
+                "# Intro\n```\na\n```\n## Usage ##\nsee `b`\n```\nc\n```\n",
+            )],
+            &with_inline(),
+        );
+        let headings: Vec<_> = blocks.iter().map(|b| b.heading()).collect();
+        assert_eq!(headings, [Some("Intro"), Some("Usage"), Some("Usage")]);
+        assert_eq!(parse_heading("#hashtag"), None);
+    }
+
+    #[test]
+    fn writes_one_json_object_per_line() {
+        let blocks = collect_blocks(
+            vec![input("a.md", "```rust\nfn a() {}\n```\n```\nb\n```\n")],
+            &ParseOptions::default(),
+        );
+        let mut out = Vec::new();
+        write_json_lines(&mut out, &blocks, JsonFields::default()).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<serde_json::Value> = text
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["lang"], "rust");
+        assert_eq!(lines[1]["code"], "b");
+    }
+
+    #[test]
+    fn tracks_heading_path_by_level() {
+        let doc = "# Guide\n## Install\n### Linux\n```\na\n```\n## Usage\n```\nb\n```\n# Other\n```\nc\n```\n";
+        let blocks = collect_blocks(vec![input("g.md", doc)], &ParseOptions::default());
+        assert_eq!(blocks[0].heading_path, ["Guide", "Install", "Linux"]);
+        assert_eq!(blocks[1].heading_path, ["Guide", "Usage"]);
+        assert_eq!(blocks[2].heading_path, ["Other"]);
+        assert!(list_line(&blocks[0], false, true).ends_with("] Guide > Install > Linux"));
+    }
+
+    #[test]
+    fn annotates_json_blocks_on_request() {
+        let blocks = collect_blocks(
+            vec![input("a.md", "```JS\nlet é;\n```\n")],
+            &ParseOptions::default(),
+        );
+        let lean = serde_json::to_value(JsonBlock::new(&blocks[0], JsonFields::default())).unwrap();
+        assert!(lean.get("chars").is_none());
+
+        let aliases = BTreeMap::from([("js".to_string(), "javascript".to_string())]);
+        let fields = JsonFields {
+            annotate: Some(&aliases),
+            ..Default::default()
+        };
+        let json = serde_json::to_value(JsonBlock::new(&blocks[0], fields)).unwrap();
+        assert_eq!(json["lines"], 1);
+        assert_eq!(json["chars"], 6);
+        assert_eq!(json["bytes"], 7);
+        assert_eq!(json["is_empty"], false);
+        assert_eq!(json["canonical_lang"], "javascript");
+    }
+
+    #[test]
+    fn block_ids_survive_moves_within_a_file() {
+        let before = collect_blocks(
+            vec![input("a.md", "```sh\nls\n```\n```rust\nfn a() {}\n```\n")],
+            &ParseOptions::default(),
+        );
+        let after = collect_blocks(
+            vec![input(
+                "a.md",
+                "Intro\n```rust\nfn a() {}\n// more\n```\n```sh\nls\n```\n",
+            )],
+            &ParseOptions::default(),
+        );
+        assert_eq!(before[1].block_id, after[0].block_id);
+        assert_eq!(before[0].block_id, after[1].block_id);
+        assert_ne!(before[0].block_id, before[1].block_id);
+        assert_eq!(before[0].block_id.len(), BLOCK_ID_LEN);
+
+        // Blocks opening with the same line, empty ones included, still get
+        // distinct IDs, and keep them when another block moves in front.
+        let twins = "```rust\nfn main() {\n}\n```\n```rust\nfn main() {\n    a();\n}\n```\n```\n```\n```\n```\n";
+        let blocks = collect_blocks(vec![input("b.md", twins)], &ParseOptions::default());
+        let ids: BTreeSet<&str> = blocks.iter().map(|b| b.block_id.as_str()).collect();
+        assert_eq!(ids.len(), 4);
+        let shifted = collect_blocks(
+            vec![input("b.md", &format!("```sh\nls\n```\n{twins}"))],
+            &ParseOptions::default(),
+        );
+        let shifted_ids: Vec<&str> = shifted[1..].iter().map(|b| b.block_id.as_str()).collect();
+        let ids: Vec<&str> = blocks.iter().map(|b| b.block_id.as_str()).collect();
+        assert_eq!(shifted_ids, ids);
+
+        let fields = JsonFields {
+            block_ids: true,
+            ..Default::default()
+        };
+        let json = serde_json::to_value(JsonBlock::new(&before[0], fields)).unwrap();
+        assert_eq!(json["block_id"], before[0].block_id);
+    }
+
+    #[test]
+    fn fits_preview_lines_to_width() {
+        assert_eq!(
+            fit_width("0: rust (3 lines) [a.md]", Some(10)),
+            "0: rust (…"
+        );
+        assert_eq!(fit_width("0: rust", Some(10)), "0: rust");
+        assert_eq!(fit_width("0: rust (3 lines)", None), "0: rust (3 lines)");
+    }
+
+    fn run_argv(argv: &[&str]) -> i32 {
+        let matches = Cli::command().try_get_matches_from(argv).unwrap();
+        let (args, matches) = split_cli(matches);
+        run(args, &matches, None).unwrap()
+    }
+
+    #[test]
+    fn keep_going_failures_survive_early_exit_modes() {
+        let dir = env::temp_dir().join(format!("mdcode-keep-going-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let ok = dir.join("ok.md");
+        fs::write(&ok, "```rust\nlet a = 1;\n```\n").unwrap();
+        let ok = ok.to_str().unwrap();
+        let missing = dir.join("missing.md");
+        let missing = missing.to_str().unwrap();
+
+        let base = ["mdcode", "--no-config", "--keep-going", ok];
+        assert_eq!(run_argv(&[&base[..], &["--warn-on-tabs"]].concat()), 0);
+        assert_eq!(
+            run_argv(&[&base[..], &[missing, "--warn-on-tabs"]].concat()),
+            1
+        );
+        assert_eq!(run_argv(&[&base[..], &["--diff", ok]].concat()), 0);
+        assert_eq!(run_argv(&[&base[..], &[missing, "--diff", ok]].concat()), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn capped_output_stops_at_the_limit_and_exits_truncated() {
+        let mut capped = Capped::new(Vec::new(), 8);
+        capped.write_all(b"abcd").unwrap();
+        assert_eq!(capped.status(), 0);
+        capped.write_all(b"efghijkl").unwrap();
+        assert_eq!(capped.inner, b"abcdefgh");
+        assert_eq!(capped.status(), TRUNCATED_EXIT);
+        assert_eq!(TRUNCATED_EXIT, 3);
+    }
+
+    fn emitted_json(style: JsonStyle, compact: bool) -> String {
+        let blocks = collect_blocks(
+            vec![input("a.md", "```rust\nfn a() {}\n```\n```sh\nls\n```\n")],
+            &ParseOptions::default(),
+        );
+        let mut out = Vec::new();
+        emit_json(&mut out, &blocks, JsonFields::default(), style, compact).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn json_objects_are_pretty_and_blank_line_separated() {
+        let out = emitted_json(JsonStyle::Objects, false);
+        assert!(out.starts_with("{\n"));
+        let objects: Vec<&str> = out.trim_end().split("\n\n").collect();
+        assert_eq!(objects.len(), 2);
+        for (object, lang) in objects.iter().zip(["rust", "sh"]) {
+            let value: serde_json::Value = serde_json::from_str(object).unwrap();
+            assert_eq!(value["lang"], lang);
+        }
+    }
+
+    #[test]
+    fn compact_json_is_a_single_line() {
+        let out = emitted_json(JsonStyle::Array, true);
+        assert_eq!(out.lines().count(), 1);
+        let value: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(value.as_array().unwrap().len(), 2);
+        assert!(emitted_json(JsonStyle::Array, false).lines().count() > 2);
+    }
+
+    #[test]
+    fn ndjson_writes_one_compact_object_per_line() {
+        let out = emitted_json(JsonStyle::Ndjson, false);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 2);
+        for (line, code) in lines.iter().zip(["fn a() {}", "ls"]) {
+            let value: serde_json::Value = serde_json::from_str(line).unwrap();
+            assert_eq!(value["code"], code);
+        }
+    }
+
+    #[test]
+    fn progress_bar_only_for_many_inputs_on_a_terminal() {
+        assert!(shows_progress(true, true, PROGRESS_MIN_INPUTS));
+        assert!(!shows_progress(true, true, PROGRESS_MIN_INPUTS - 1));
+        assert!(!shows_progress(true, false, PROGRESS_MIN_INPUTS));
+        assert!(!shows_progress(false, true, PROGRESS_MIN_INPUTS));
+
+        let bar = progress_bar(PROGRESS_MIN_INPUTS);
+        assert_eq!(bar.length(), Some(PROGRESS_MIN_INPUTS as u64));
+        bar.inc(1);
+        assert_eq!(bar.position(), 1);
+    }
+
+    #[test]
+    fn reverse_files_reverses_input_order() {
+        let dir = env::temp_dir().join(format!("mdcode-reverse-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let paths: Vec<String> = ["a.md", "b.md"]
+            .iter()
+            .map(|name| {
+                let path = dir.join(name);
+                fs::write(&path, "```\nx\n```\n").unwrap();
+                path.display().to_string()
+            })
+            .collect();
+        let names = |flags: &[&str]| {
+            let argv = [&["mdcode"], flags, &[&paths[0], &paths[1]]].concat();
+            let (sources, _) =
+                collect_inputs(&Cli::try_parse_from(argv).unwrap().args, None).unwrap();
+            sources.into_iter().map(|s| s.name).collect::<Vec<_>>()
+        };
+        assert_eq!(names(&[]), paths);
+        assert_eq!(names(&["--reverse-files"]), [paths[1].as_str(), &paths[0]]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn allow_empty_turns_no_match_into_success() {
+        let dir = env::temp_dir().join(format!("mdcode-allow-empty-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let doc = dir.join("a.md");
+        fs::write(&doc, "```rust\nlet a = 1;\n```\n").unwrap();
+        let doc = doc.to_str().unwrap();
+
+        assert_eq!(
+            run_argv(&["mdcode", "--no-config", "--lang=python", doc]),
+            1
+        );
+        assert_eq!(
+            run_argv(&[
+                "mdcode",
+                "--no-config",
+                "--lang=python",
+                "--allow-empty",
+                doc
+            ]),
+            0
+        );
+        assert_eq!(
+            run_argv(&["mdcode", "--no-config", "--region", "nope", doc]),
+            1
+        );
+        assert_eq!(
+            run_argv(&[
+                "mdcode",
+                "--no-config",
+                "--region",
+                "nope",
+                "--allow-empty",
+                doc
+            ]),
+            0
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dry_run_leaves_disk_untouched() {
+        let blocks = collect_blocks(
+            vec![input("a.md", "```rust\nfn a() {}\n```\n")],
+            &ParseOptions::default(),
+        );
+        let dir = format!("mdcode-dry-run-test-{}", std::process::id());
+        let template = format!("{dir}/{{index}}.{{ext}}");
+        let outputs = plan_outputs(&blocks, &template, false).unwrap();
+        write_outputs(&outputs, true).unwrap();
+        write_source_map(&Path::new(&dir).join("map.json"), Vec::new(), true).unwrap();
+        assert!(!Path::new(&dir).exists());
+    }
+
+    #[test]
+    fn manifest_entries_name_output_and_lang() {
+        let blocks = collect_blocks(
+            vec![input("docs/a.md", "```rust\nfn a() {}\n```\n")],
+            &ParseOptions::default(),
+        );
+        let outputs = plan_outputs(&blocks, "out/{index}.{ext}", false).unwrap();
+        let (path, block) = &outputs[0];
+        let json = serde_json::to_value(SourceMapEntry::new(block, Some(path))).unwrap();
+        assert_eq!(json["output"], "out/0.rs");
+        assert_eq!(json["source"], "docs/a.md");
+        assert_eq!(json["lang"], "rust");
+        assert_eq!(json["start_line"], 2);
+        assert_eq!(json["end_line"], 2);
+    }
+
+    #[test]
+    fn archives_blocks_as_tar_entries() {
+        let blocks = collect_blocks(
+            vec![input("a.md", "```rust\nfn a() {}\n```\n```\nnotes\n```\n")],
+            &ParseOptions::default(),
+        );
+        let outputs = plan_outputs(&blocks, DEFAULT_TAR_TEMPLATE, false).unwrap();
+        let bytes = write_tar(Vec::new(), &outputs, 0).unwrap();
+
+        let mut archive = tar::Archive::new(bytes.as_slice());
+        let entries: Vec<(String, String)> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let name = entry.path().unwrap().display().to_string();
+                let mut body = String::new();
+                entry.read_to_string(&mut body).unwrap();
+                (name, body)
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                ("0.rs".to_string(), "fn a() {}\n".to_string()),
+                ("1.txt".to_string(), "notes\n".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn strips_doctest_markers() {
+        let rust =
+            "# use std::fmt;\n#\n#[derive(Debug)]\nstruct A;\n    # let a = A;\n## not hidden";
+        assert_eq!(
+            strip_doctest(Some("rust"), rust).unwrap(),
+            "use std::fmt;\n\n#[derive(Debug)]\nstruct A;\n    let a = A;\n# not hidden"
+        );
+        let python = ">>> def f():\n...     return 1\n...\n>>> f()\n1";
+        assert_eq!(
+            strip_doctest(Some("py"), python).unwrap(),
+            "def f():\n    return 1\n\nf()"
+        );
+        assert_eq!(
+            strip_doctest(Some("python"), "x = 1  # ... >>>").unwrap(),
+            "x = 1  # ... >>>"
+        );
+        assert_eq!(strip_doctest(Some("sh"), "# ls"), None);
+    }
+
+    #[test]
+    fn strips_leading_prompts() {
+        let code = "$ cargo build\n# apt install x\n> continued\noutput $ here\n$";
+        assert_eq!(
+            strip_prompts(code, "$#"),
+            "cargo build\napt install x\n> continued\noutput $ here\n$"
+        );
+        assert_eq!(strip_prompts("> echo", ">"), "echo");
+        assert_eq!(prompt_chars(Some("console"), "$#"), "$#");
+        assert_eq!(prompt_chars(Some("bash"), "$#"), "$");
+        let script = "# install deps\n$ make";
+        assert_eq!(
+            strip_prompts(script, &prompt_chars(Some("sh"), "$#")),
+            "# install deps\nmake"
+        );
+        assert!(is_shell_lang(Some("Bash")) && is_shell_lang(Some("console")));
+        assert!(!is_shell_lang(Some("python")) && !is_shell_lang(None));
+    }
+
+    #[test]
+    fn maps_global_to_per_source_indices() {
+        let blocks = collect_blocks(
+            vec![
+                input("a.md", "```rust\na\n```\n"),
+                input("b.md", "```\nb\nc\n```\n```sh\nd\n```\n"),
+            ],
+            &ParseOptions::default(),
+        );
+        assert_eq!(
+            render_index_map(&blocks),
+            "INDEX  SOURCE  SOURCE_INDEX  LANG   LINES\n\
+             0      a.md    0             rust   2\n\
+             1      b.md    0             plain  2-3\n\
+             2      b.md    1             sh     6\n"
+        );
+
+        let filter = parse_index_filter(Some("1-2")).unwrap().unwrap();
+        assert_eq!(
+            render_index_map(&apply_index_filter(blocks, filter)),
+            "INDEX  SOURCE  SOURCE_INDEX  LANG   LINES\n\
+             1      b.md    0             plain  2-3\n\
+             2      b.md    1             sh     6\n"
+        );
+    }
+
+    #[test]
+    fn caches_parses_by_content() {
+        let dir = env::temp_dir().join(format!("mdcode-cache-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let parse = ParseOptions {
+            cache_dir: Some(dir.clone()),
+            ..Default::default()
+        };
+        let doc = "# T\n```rust\nfn a() {}\n```\n";
+        let fresh = collect_blocks(vec![input("a.md", doc)], &parse);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        let cached = collect_blocks(vec![input("a.md", doc)], &parse);
+        assert_eq!(cached[0].code, fresh[0].code);
+        assert_eq!(cached[0].fence_open.as_deref(), Some("```rust"));
+        assert_eq!(cached[0].heading_levels, [1]);
+
+        // Options that change the parse get their own entries.
+        let inline = ParseOptions {
+            include_inline: true,
+            ..parse
+        };
+        collect_blocks(vec![input("a.md", doc)], &inline);
+        collect_blocks(vec![input("a.md", "```\nchanged\n```\n")], &inline);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cache_hits_repeat_parse_warnings() {
+        let dir = env::temp_dir().join(format!("mdcode-cache-warn-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let parse = ParseOptions {
+            cache_dir: Some(dir.clone()),
+            ..Default::default()
+        };
+        let notebook = || vec![input("broken.ipynb", "{not json")];
+        let (_, miss) = capture_warnings(|| collect_blocks(notebook(), &parse));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        let (_, hit) = capture_warnings(|| collect_blocks(notebook(), &parse));
+        assert_eq!(miss.len(), 1);
+        assert!(miss[0].message.contains("not a valid notebook"));
+        assert_eq!(hit, miss);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn detects_remote_inputs() {
+        assert_eq!(
+            remote_url(Path::new("https://example.com/README.md")),
+            Some("https://example.com/README.md")
+        );
+        assert_eq!(
+            remote_url(Path::new("http://x/a.md")),
+            Some("http://x/a.md")
+        );
+        assert_eq!(remote_url(Path::new("docs/https.md")), None);
+    }
+
+    #[test]
+    fn splits_output_on_heading_level() {
+        let doc = "```\n0\n```\n# Setup\n## Linux\n```\na\n```\n```\nb\n```\n# Usage Notes\n```\nc\n```\n";
+        let mut blocks = collect_blocks(vec![input("g.md", doc)], &ParseOptions::default());
+        for block in &mut blocks {
+            block.section = block.heading_at(1).map(str::to_string);
+        }
+        let render = RenderOptions {
+            sections: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            raw_output(&blocks, &render, "\n", None, None),
+            "=== (preamble) ===\n0\n=== Setup ===\na\nb\n=== Usage Notes ===\nc"
+        );
+        assert_eq!(blocks[1].heading_at(2), Some("Linux"));
+        assert_eq!(
+            expand_template("{section}/{index}.{ext}", &blocks[3]),
+            PathBuf::from("usage-notes/3.txt")
+        );
+        assert_eq!(section_slug(None), "preamble");
+    }
+
+    #[test]
+    fn formats_list_lines_from_template() {
+        let doc = "# Guide\n```rust\nfn main() {}\n```\n";
+        let blocks = collect_blocks(vec![input("g.md", doc)], &ParseOptions::default());
+        assert_eq!(
+            format_list_line(
+                "{source}:{start_line}:{lang} ({lines}, {kind}) {heading}",
+                &blocks[0]
+            ),
+            "g.md:3:rust (1, fenced) Guide"
+        );
+    }
+
+    #[test]
+    fn strips_comments_by_language() {
+        let rust = comment_syntax(Some("rust")).unwrap();
+        let code = "// header\nlet url = \"http://x\"; // trailing\n/* one\n two */ let b = 1; /* c */\n\nlet c = a//b;";
+        assert_eq!(
+            strip_comments(code, rust),
+            "let url = \"http://x\";\n let b = 1;\n\nlet c = a//b;"
+        );
+
+        let sh = comment_syntax(Some("sh")).unwrap();
+        assert_eq!(
+            strip_comments("echo $# \"#x\" # note\n  # only", sh),
+            "echo $# \"#x\""
+        );
+        assert_eq!(comment_syntax(Some("brainfuck")), None);
+    }
+
+    #[test]
+    fn source_comment_uses_language_syntax() {
+        let mut block = CodeBlock {
+            source: "doc.md".into(),
+            lang: Some("rust".into()),
+            start_line: Some(12),
+            code: "fn main() {}".into(),
+            ..Default::default()
+        };
+        let render = RenderOptions {
+            fenced: true,
+            source_comment: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            render_block(&block, &render),
+            "```rust\n// doc.md:12\nfn main() {}\n```"
+        );
 
-    while i < bytes.len() {
-        if bytes[i] == b'`' {
-            let mut tick_len = 1;
-            while i + tick_len < bytes.len() && bytes[i + tick_len] == b'`' {
-                tick_len += 1;
-            }
-            if start_tick.is_none() {
-                start_tick = Some(tick_len);
-                start_idx = Some(i + tick_len);
-            } else if let Some(open_ticks) = start_tick {
-                if tick_len == open_ticks {
-                    let content_start = start_idx.unwrap_or(i);
-                    let content = line[content_start..i].to_string();
-                    if !content.is_empty() {
-                        blocks.push(CodeBlock {
-                            index: 0,
-                            source: source.to_string(),
-                            kind: BlockKind::Inline,
-                            lang: None,
-                            start_line: Some(line_no),
-                            end_line: Some(line_no),
-                            code: content,
-                        });
-                    }
-                    start_tick = None;
-                    start_idx = None;
-                }
-            }
-            i += tick_len;
-        } else {
-            i += 1;
-        }
+        block.lang = Some("html".into());
+        assert_eq!(source_comment(&block), "<!-- doc.md:12 -->");
+        block.lang = Some("brainfuck".into());
+        assert_eq!(source_comment(&block), "# doc.md:12");
     }
 
-    blocks
-}
+    #[test]
+    fn fence_widens_past_inner_backticks() {
+        assert_eq!(fence_for("plain", '`'), "```");
+        assert_eq!(fence_for("```rust\n```", '`'), "````");
+    }
 
-fn matches_lang(block: &CodeBlock, lang: &str) -> bool {
-    block
-        .lang
-        .as_deref()
-        .map(|b| b.eq_ignore_ascii_case(lang))
-        .unwrap_or(false)
-}
+    #[test]
+    fn renders_markdown_document() {
+        let blocks = collect_blocks(
+            vec![input(
+                "file.md",
+                "# A\n```rust\none\n```\n```rust\ntwo\n```\n# B\n```sh\nthree\n```\n",
+            )],
+            &ParseOptions::default(),
+        );
+        let expected =
+            "## A\n\n```rust\none\n```\n\n```rust\ntwo\n```\n\n## B\n\n```sh\nthree\n```\n";
+        assert_eq!(
+            render_markdown(&blocks, &RenderOptions::default()),
+            expected
+        );
 
-fn parse_index_filter(
-    raw: Option<&str>,
-) -> Result<Option<IndexFilter>, Box<dyn std::error::Error>> {
-    let Some(raw) = raw else {
-        return Ok(None);
-    };
+        let tilde = RenderOptions {
+            fence_char: FenceChar::Tilde,
+            ..Default::default()
+        };
+        assert_eq!(
+            render_markdown(&blocks[2..], &tilde),
+            "## B\n\n~~~sh\nthree\n~~~\n"
+        );
+    }
 
-    if let Some((start, end)) = raw.split_once('-') {
-        let start = start.trim().parse::<usize>()?;
-        let end = end.trim().parse::<usize>()?;
-        if start > end {
-            return Err("range start must be <= end".into());
-        }
-        Ok(Some(IndexFilter::Range { start, end }))
-    } else {
-        let value = raw.trim().parse::<usize>()?;
-        Ok(Some(IndexFilter::Single(value)))
+    #[test]
+    fn splits_session_commands_from_output() {
+        let code = "$ cargo build\n   Compiling x\n# apt install \\\n    jq\noutput";
+        assert_eq!(
+            session_commands(code),
+            ["cargo build", "apt install \\\n    jq"]
+        );
+        assert!(is_session_lang(Some("Console")));
+        assert!(!is_session_lang(Some("bash")));
     }
-}
 
-fn apply_index_filter(blocks: Vec<CodeBlock>, filter: IndexFilter) -> Vec<CodeBlock> {
-    match filter {
-        IndexFilter::Single(n) => blocks.into_iter().filter(|b| b.index == n).collect(),
-        IndexFilter::Range { start, end } => blocks
-            .into_iter()
-            .filter(|b| b.index >= start && b.index <= end)
-            .collect(),
+    #[test]
+    fn decodes_legacy_encodings() {
+        let latin1 = b"caf\xe9";
+        assert!(decode_input(latin1, "a.md", encoding_rs::UTF_8).is_err());
+
+        let latin1_encoding = Encoding::for_label(b"latin1").unwrap();
+        assert_eq!(
+            decode_input(latin1, "a.md", latin1_encoding).unwrap(),
+            "café"
+        );
     }
-}
 
-fn list_languages(blocks: &[CodeBlock]) {
-    let mut langs = BTreeSet::new();
-    for block in blocks {
-        if let Some(lang) = &block.lang {
-            langs.insert(lang.to_string());
-        }
+    #[test]
+    fn selects_blocks_by_hash_prefix() {
+        let doc = "```\nalpha\n```\n```\nbeta\n```\n```\nalpha\n```\n";
+        let parse = ParseOptions::default();
+        let blocks = || collect_blocks(vec![input("a.md", doc)], &parse);
+        let alpha = content_hash("alpha");
+        assert_eq!(blocks()[0].hash, alpha);
+        assert_eq!(alpha.len(), 64);
+
+        let selected = select_by_hash(blocks(), &alpha[..8].to_uppercase()).unwrap();
+        let indices: Vec<usize> = selected.iter().map(|b| b.index).collect();
+        assert_eq!(indices, [0, 2]);
+
+        assert!(select_by_hash(blocks(), "").is_err());
+        assert!(select_by_hash(blocks(), "xyz").is_err());
+
+        let ambiguous = vec![
+            CodeBlock {
+                hash: "ab12".into(),
+                ..Default::default()
+            },
+            CodeBlock {
+                hash: "ab34".into(),
+                ..Default::default()
+            },
+        ];
+        assert!(select_by_hash(ambiguous, "ab").is_err());
     }
 
-    for lang in langs {
-        println!("{lang}");
+    #[test]
+    fn classifies_blocks_by_language() {
+        let doc =
+            "```mermaid\na\n```\n```Math\nb\n```\n```rust\nc\n```\n```puml\nd\n```\n```\ne\n```\n";
+        let blocks = collect_blocks(vec![input("a.md", doc)], &ParseOptions::default());
+        let categories = BTreeMap::from([("plantuml".to_string(), "uml".to_string())]);
+        let aliases = BTreeMap::from([("puml".to_string(), "plantuml".to_string())]);
+        let found: Vec<String> = blocks
+            .iter()
+            .map(|b| block_category(b, &categories, &aliases))
+            .collect();
+        assert_eq!(found, ["diagram", "math", "code", "uml", "code"]);
     }
-}
 
-fn emit_json(
-    blocks: &[CodeBlock],
-    include_line_numbers: bool,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let payload: Vec<JsonBlock> = blocks
-        .iter()
-        .map(|b| JsonBlock {
-            index: b.index,
-            source: b.source.clone(),
-            kind: b.kind,
-            lang: b.lang.clone(),
-            start_line: include_line_numbers.then_some(b.start_line).flatten(),
-            end_line: include_line_numbers.then_some(b.end_line).flatten(),
-            code: b.code.clone(),
-        })
-        .collect();
+    #[test]
+    fn finds_block_containing_a_line() {
+        let doc = "intro\n```rust\nfn a() {}\n```\ntext `x`\n```\nopen\n";
+        let blocks = collect_blocks(vec![input("a.md", doc)], &with_inline());
+        let at = |line| -> Vec<usize> {
+            blocks
+                .iter()
+                .filter(|b| contains_line(b, line))
+                .map(|b| b.index)
+                .collect()
+        };
+        assert!(at(1).is_empty());
+        assert_eq!(at(2), [0]);
+        assert_eq!(at(4), [0]);
+        assert_eq!(at(5), [1]);
+        assert_eq!(at(7), [2]);
+    }
 
-    serde_json::to_writer_pretty(io::stdout(), &payload)?;
-    println!();
-    Ok(())
-}
+    #[test]
+    fn expands_input_globs_with_braces() {
+        let dir = env::temp_dir().join(format!("mdcode-glob-test-{}", std::process::id()));
+        for file in [
+            "guide/a.md",
+            "guide/deep/B.MD",
+            "api/c.md",
+            "other/d.md",
+            "guide/e.txt",
+            "Notes/f.md",
+        ] {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        let root = dir.display();
+        let expand = |pattern: String, case_insensitive| {
+            expand_inputs(&[PathBuf::from(pattern)], case_insensitive, None).map(|paths| {
+                paths
+                    .iter()
+                    .map(|p| p.strip_prefix(&dir).unwrap().display().to_string())
+                    .collect::<Vec<_>>()
+            })
+        };
 
-#[derive(Debug, Serialize)]
-struct JsonBlock {
-    index: usize,
-    source: String,
-    kind: BlockKind,
-    lang: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    start_line: Option<usize>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    end_line: Option<usize>,
-    code: String,
-}
+        assert_eq!(
+            expand(format!("{root}/{{guide,api}}/**/*.md"), false).unwrap(),
+            ["api/c.md", "guide/a.md"]
+        );
+        assert_eq!(
+            expand(format!("{root}/{{guide,api}}/**/*.md"), true).unwrap(),
+            ["api/c.md", "guide/a.md", "guide/deep/B.MD"]
+        );
+        assert_eq!(
+            expand(format!("{root}/*/*.txt"), false).unwrap(),
+            ["guide/e.txt"]
+        );
+        assert_eq!(
+            expand(format!("{root}/notes/*.md"), true).unwrap(),
+            ["Notes/f.md"]
+        );
+        assert!(expand(format!("{root}/*.rst"), false).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
 
-fn print_list(blocks: &[CodeBlock], include_line_numbers: bool) {
-    for block in blocks {
-        let lang = block.lang.clone().unwrap_or_else(|| "plain".to_string());
-        let lines = line_count(&block.code);
-        let location = if include_line_numbers {
-            match (block.start_line, block.end_line) {
-                (Some(start), Some(end)) if start != end => {
-                    format!("{}:{}-{}", block.source, start, end)
-                }
-                (Some(line), _) => format!("{}:{}", block.source, line),
-                _ => block.source.clone(),
-            }
-        } else {
-            block.source.clone()
+    #[cfg(unix)]
+    #[test]
+    fn recursive_scan_follows_symlinks_without_cycling() {
+        let dir = env::temp_dir().join(format!("mdcode-recursive-test-{}", std::process::id()));
+        let shared = dir.join("shared");
+        let docs = dir.join("docs");
+        fs::create_dir_all(&shared).unwrap();
+        fs::create_dir_all(docs.join("sub")).unwrap();
+        fs::write(docs.join("a.md"), "").unwrap();
+        fs::write(docs.join("sub/b.rst"), "").unwrap();
+        fs::write(docs.join("notes.txt"), "").unwrap();
+        fs::write(shared.join("c.md"), "").unwrap();
+        std::os::unix::fs::symlink(&shared, docs.join("shared")).unwrap();
+        std::os::unix::fs::symlink(&docs, docs.join("sub/loop")).unwrap();
+        let scan = |follow_symlinks| {
+            let mut walk = Walk {
+                follow_symlinks,
+                ..Default::default()
+            };
+            expand_inputs(std::slice::from_ref(&docs), false, Some(&mut walk))
+                .unwrap()
+                .iter()
+                .map(|p| p.strip_prefix(&docs).unwrap().display().to_string())
+                .collect::<Vec<_>>()
         };
 
-        println!("{}: {} ({} lines) [{}]", block.index, lang, lines, location);
+        assert_eq!(scan(false), ["a.md", "sub/b.rst"]);
+        assert_eq!(scan(true), ["a.md", "shared/c.md", "sub/b.rst"]);
+        fs::remove_dir_all(&dir).unwrap();
     }
-}
 
-fn print_raw(blocks: &[CodeBlock], fenced: bool, line_numbers: bool, separator: &str) {
-    let rendered: Vec<String> = blocks
-        .iter()
-        .map(|b| render_block(b, fenced, line_numbers))
-        .collect();
+    #[test]
+    fn matches_sources_by_name_or_glob() {
+        let exact = source_matcher("guide.md", false).unwrap();
+        assert!(exact("docs/guide.md"));
+        assert!(exact("guide.md"));
+        assert!(!exact("docs/guide.md.bak"));
 
-    print!("{}", rendered.join(separator));
-    if !rendered.is_empty() && !separator.ends_with('\n') {
-        println!();
+        let glob = source_matcher("docs/*.md", false).unwrap();
+        assert!(glob("docs/a.md"));
+        assert!(!glob("other/a.md"));
+        assert!(!glob("DOCS/A.MD"));
+        assert!(source_matcher("docs/*.md", true).unwrap()("DOCS/A.MD"));
+        assert!(source_matcher("[", false).is_err());
     }
-}
 
-fn render_block(block: &CodeBlock, fenced: bool, line_numbers: bool) -> String {
-    let mut content = if line_numbers {
-        let start = block.start_line.unwrap_or(1);
-        add_line_numbers(&block.code, start)
-    } else {
-        block.code.clone()
-    };
+    #[test]
+    fn sorts_blocks_inside_each_source() {
+        let mut blocks = collect_blocks(
+            vec![
+                input("b.md", "```sh\n1\n2\n```\n```\n3\n```\n```Rust\n4\n```\n"),
+                input("a.md", "```toml\n5\n```\n```c\n6\n7\n8\n```\n"),
+            ],
+            &ParseOptions::default(),
+        );
+        sort_within_source(&mut blocks, SortKey::Lang);
+        let order: Vec<usize> = blocks.iter().map(|b| b.index).collect();
+        assert_eq!(order, [2, 0, 1, 4, 3]);
 
-    if fenced {
-        let lang = block.lang.clone().unwrap_or_default();
-        let fence = if lang.is_empty() {
-            "```".to_string()
-        } else {
-            format!("```{}", lang)
-        };
-        content = format!("{fence}\n{content}\n```");
+        sort_within_source(&mut blocks, SortKey::Size);
+        let order: Vec<usize> = blocks.iter().map(|b| b.index).collect();
+        assert_eq!(order, [2, 1, 0, 3, 4]);
     }
 
-    content
-}
+    #[test]
+    fn parses_language_allowlists() {
+        let text = "# allowed in docs\nRust\n\n  toml  # config\nsh\n";
+        assert_eq!(parse_lang_list(text), ["rust", "toml", "sh"]);
+    }
 
-fn add_line_numbers(content: &str, start_line: usize) -> String {
-    content
-        .lines()
-        .enumerate()
-        .map(|(idx, line)| format!("{:>6}: {}", start_line + idx, line))
-        .collect::<Vec<_>>()
-        .join("\n")
-}
+    #[test]
+    fn detects_input_encodings() {
+        assert_eq!(detect_encoding("café".as_bytes()), encoding_rs::UTF_8);
+        assert_eq!(detect_encoding(b"plain ascii"), encoding_rs::UTF_8);
+        let (latin1, _, _) =
+            encoding_rs::WINDOWS_1252.encode("Le café où l'été déjà s'éloigne, très élégant.");
+        assert_eq!(detect_encoding(&latin1), encoding_rs::WINDOWS_1252);
+    }
 
-fn line_count(content: &str) -> usize {
-    if content.is_empty() {
-        0
-    } else {
-        content.lines().count()
+    #[test]
+    fn counts_blocks_per_source() {
+        let blocks = collect_blocks(
+            vec![
+                input("a.md", "```\na\n```\n```\nb\n```\n"),
+                input("b.md", ""),
+            ],
+            &ParseOptions::default(),
+        );
+        let names = ["a.md".to_string(), "b.md".to_string()];
+        assert_eq!(source_counts(&names, &blocks), [("a.md", 2), ("b.md", 0)]);
+
+        let filter = parse_index_filter(Some("1")).unwrap().unwrap();
+        let selected = apply_index_filter(blocks, filter);
+        assert_eq!(source_counts(&names, &selected), [("a.md", 1), ("b.md", 0)]);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn expands_output_templates() {
+        let block = CodeBlock {
+            index: 2,
+            source: "docs/guide.md".into(),
+            lang: Some("rust".into()),
+            ..Default::default()
+        };
+        let path = expand_template("out/{source_dir}/{source_stem}/{index}.{ext}", &block);
+        assert_eq!(path, PathBuf::from("out/docs/guide/2.rs"));
 
-    fn input(name: &str, content: &str) -> InputSource {
-        InputSource {
-            name: name.to_string(),
-            content: content.to_string(),
-        }
+        let stdin = CodeBlock {
+            source: "stdin".into(),
+            ..Default::default()
+        };
+        let path = expand_template("{source_dir}/{source_stem}-{lang}.{ext}", &stdin);
+        assert_eq!(path, PathBuf::from("./stdin-plain.txt"));
     }
 
     #[test]
-    fn parses_fenced_block_with_lang() {
+    fn merges_blocks_with_header_and_footer() {
         let blocks = collect_blocks(
-            vec![input("file.md", "```rust\nfn main() {}\n```\n")],
-            false,
+            vec![input(
+                "a.md",
+                "```python\nimport os\n```\n```python\nprint(os.sep)\n```\n",
+            )],
+            &ParseOptions::default(),
         );
-        assert_eq!(blocks.len(), 1);
-        let b = &blocks[0];
-        assert_eq!(b.source, "file.md");
-        assert_eq!(b.kind, BlockKind::Fenced);
-        assert_eq!(b.lang.as_deref(), Some("rust"));
-        assert_eq!(b.code, "fn main() {}");
-        assert_eq!(b.start_line, Some(2));
-        assert_eq!(b.end_line, Some(2));
-        assert_eq!(b.index, 0);
+        assert_eq!(
+            merge_blocks(&blocks, Some("#!/usr/bin/env python3"), Some("# end")),
+            "#!/usr/bin/env python3\nimport os\n\nprint(os.sep)\n# end\n"
+        );
+        assert_eq!(merge_blocks(&blocks[..1], None, None), "import os\n");
     }
 
     #[test]
-    fn parses_inline_blocks_when_enabled() {
-        let blocks = collect_blocks(vec![input("file.md", "a `one` b `two`")], true);
-        assert_eq!(blocks.len(), 2);
-        assert!(blocks.iter().all(|b| b.kind == BlockKind::Inline));
-        assert_eq!(blocks[0].code, "one");
-        assert_eq!(blocks[1].code, "two");
-        assert_eq!(blocks[0].start_line, Some(1));
-        assert_eq!(blocks[1].start_line, Some(1));
+    fn output_collisions_require_force() {
+        let blocks = collect_blocks(
+            vec![input("a.md", "```rust\na\n```\n```rust\nb\n```\n")],
+            &ParseOptions::default(),
+        );
+        assert!(plan_outputs(&blocks, "out/{lang}.{ext}", false).is_err());
+        assert_eq!(
+            plan_outputs(&blocks, "out/{lang}.{ext}", true)
+                .unwrap()
+                .len(),
+            2
+        );
+        assert!(plan_outputs(&blocks, "out/{index}.{ext}", false).is_ok());
     }
 
     #[test]
-    fn ignores_inline_when_flag_disabled() {
-        let blocks = collect_blocks(vec![input("file.md", "a `one` b `two`")], false);
-        assert!(blocks.is_empty());
+    fn hostile_info_strings_stay_inside_the_output_directory() {
+        let blocks = collect_blocks(
+            vec![input(
+                "a.md",
+                "```../../../home/u/.bashrc\necho owned\n```\n",
+            )],
+            &ParseOptions::default(),
+        );
+        let outputs = plan_outputs(&blocks, "out/{lang}.{ext}", false).unwrap();
+        assert_eq!(outputs[0].0, PathBuf::from("out/home-u-bashrc.txt"));
+
+        let escaping = collect_blocks(
+            vec![input("../../etc/a.md", "```sh\nls\n```\n")],
+            &ParseOptions::default(),
+        );
+        assert!(plan_outputs(&escaping, "{source_dir}/{index}.{ext}", false).is_err());
+        assert!(plan_outputs(&blocks, "/tmp/{index}.{ext}", false).is_err());
     }
 
     #[test]
-    fn handles_unterminated_fence() {
-        let blocks = collect_blocks(vec![input("file.md", "```js\nconsole.log('x');")], false);
-        assert_eq!(blocks.len(), 1);
-        let b = &blocks[0];
-        assert_eq!(b.kind, BlockKind::Fenced);
-        assert_eq!(b.lang.as_deref(), Some("js"));
-        assert_eq!(b.start_line, Some(2));
-        assert_eq!(b.end_line, Some(2));
-        assert_eq!(b.code, "console.log('x');");
+    fn highlights_matches_per_line() {
+        let pattern = Regex::new(r"o+|x*").unwrap();
+        assert_eq!(
+            highlight_matches("foo\nbar\nboo", &pattern),
+            "f\x1b[1;31moo\x1b[0m\nbar\nb\x1b[1;31moo\x1b[0m"
+        );
+        let spanning = Regex::new(r"a\nb").unwrap();
+        assert_eq!(highlight_matches("a\nb", &spanning), "a\nb");
     }
 
     #[test]
-    fn assigns_indices_across_sources() {
+    fn escapes_code_for_targets() {
+        let code = "echo 'hi'\n\tsay \"\\\u{1}\"";
+        assert_eq!(
+            escape_code(code, EscapeTarget::Shell),
+            "'echo '\\''hi'\\''\n\tsay \"\\\u{1}\"'"
+        );
+        assert_eq!(
+            escape_code(code, EscapeTarget::Json),
+            r#""echo 'hi'\n\tsay \"\\\u0001\"""#
+        );
+        assert_eq!(
+            escape_code(code, EscapeTarget::C),
+            r#""echo 'hi'\n\tsay \"\\\001\"""#
+        );
+    }
+
+    #[test]
+    fn final_newline_can_be_forced() {
         let blocks = collect_blocks(
-            vec![input("a.md", "```txt\na\n```\n"), input("b.md", "text `x`")],
-            true,
+            vec![input("a.md", "```\none\n```\n```\ntwo\n```\n")],
+            &ParseOptions::default(),
         );
-        assert_eq!(blocks.len(), 2);
-        assert_eq!(blocks[0].source, "a.md");
-        assert_eq!(blocks[0].index, 0);
-        assert_eq!(blocks[1].source, "b.md");
-        assert_eq!(blocks[1].index, 1);
-        assert_eq!(blocks[1].kind, BlockKind::Inline);
+        let render = RenderOptions::default();
+        let out = |sep, forced| raw_output(&blocks, &render, sep, None, forced);
+        assert_eq!(out("\n", None), "one\ntwo");
+        assert_eq!(out("---", None), "one---two\n");
+        assert_eq!(out("\n", Some(true)), "one\ntwo\n");
+        assert_eq!(out("---", Some(false)), "one---two");
+        assert_eq!(raw_output(&[], &render, "---", None, Some(true)), "");
     }
 
     #[test]
-    fn matches_lang_case_insensitive() {
-        let block = CodeBlock {
-            index: 0,
-            source: "file.md".into(),
-            kind: BlockKind::Fenced,
-            lang: Some("Rust".into()),
-            start_line: None,
-            end_line: None,
-            code: String::new(),
+    fn skip_empty_output_drops_blank_bodies_and_their_separators() {
+        let blocks = collect_blocks(
+            vec![
+                input("a.md", "```\none\n```\n```\n  \n```\n"),
+                input("b.md", "```\n\n```\n"),
+            ],
+            &ParseOptions::default(),
+        );
+        let render = RenderOptions {
+            skip_empty: true,
+            ..Default::default()
         };
-        assert!(matches_lang(&block, "rust"));
-        assert!(!matches_lang(&block, "python"));
+        assert_eq!(
+            raw_output(&blocks, &render, "---", Some("# {source}"), None),
+            "one\n"
+        );
+        assert_eq!(raw_output(&blocks[1..], &render, "---", None, None), "");
     }
 
     #[test]
-    fn parses_index_filters() {
-        match parse_index_filter(Some("3")).unwrap() {
-            Some(IndexFilter::Single(3)) => {}
-            other => panic!("unexpected: {:?}", other),
-        }
-
-        match parse_index_filter(Some("1-4")).unwrap() {
-            Some(IndexFilter::Range { start, end }) => {
-                assert_eq!(start, 1);
-                assert_eq!(end, 4);
-            }
-            other => panic!("unexpected: {:?}", other),
-        }
+    fn file_separator_only_between_sources() {
+        let blocks = collect_blocks(
+            vec![
+                input("a.md", "```\none\n```\n```\ntwo\n```\n"),
+                input("b.md", "```\nthree\n```\n"),
+            ],
+            &ParseOptions::default(),
+        );
 
-        assert!(parse_index_filter(Some("4-2")).is_err());
+        let joined = join_rendered(
+            &blocks,
+            &RenderOptions::default(),
+            "\n",
+            Some("== {source} =="),
+        );
+        assert_eq!(joined, "one\ntwo\n== b.md ==\nthree");
     }
 
     #[test]
-    fn renders_fenced_with_line_numbers() {
-        let block = CodeBlock {
-            index: 0,
-            source: "file.md".into(),
-            kind: BlockKind::Fenced,
-            lang: Some("rs".into()),
-            start_line: Some(10),
-            end_line: Some(11),
-            code: "fn a() {}\nfn b() {}".into(),
-        };
+    fn gutter_width_fits_largest_line_number() {
+        assert_eq!(
+            add_line_numbers("a\nb", 999, None, None),
+            " 999: a\n1000: b"
+        );
+        assert_eq!(add_line_numbers("a", 7, Some(6), None), "     7: a");
+    }
 
-        let rendered = render_block(&block, true, true);
-        let expected = "```rs\n    10: fn a() {}\n    11: fn b() {}\n```";
-        assert_eq!(rendered, expected);
+    #[test]
+    fn number_lines_leaves_blank_gutters() {
+        let selected = parse_line_selection("2,4-5").unwrap();
+        assert_eq!(
+            add_line_numbers("a\nb\nc\nd\ne", 10, None, Some(&selected)),
+            "     a\n 11: b\n     c\n 13: d\n 14: e"
+        );
+        assert!(parse_line_selection("0").is_err());
+        assert!(parse_line_selection("3-1").is_err());
     }
 }